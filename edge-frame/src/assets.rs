@@ -1,316 +1,201 @@
-pub const MAX_ASSETS: usize = 10;
+#[cfg(feature = "assets-serve-core")]
+pub mod serve;
 
-#[cfg(feature = "assets-serve")]
-pub mod serve {
-    use core::fmt::Debug;
-
-    use embedded_svc::http::server::{Connection, HandlerResult, Request};
-    use embedded_svc::utils::http::Headers;
-
-    use embedded_svc::io::Write;
-
-    pub type Asset = (&'static str, &'static [u8]);
-
-    pub type Assets = [Asset; super::MAX_ASSETS];
-
-    #[macro_export]
-    macro_rules! assets {
-        ($module:literal) => {
-            [
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_0")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_0"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_1")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_1"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_2")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_2"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_3")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_3"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_4")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_4"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_5")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_5"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_6")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_6"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_7")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_7"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_8")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_8"))),
-                ),
-                (
-                    env!(concat!($module, "_EDGE_FRAME_ASSET_URI_9")),
-                    include_bytes!(env!(concat!($module, "_EDGE_FRAME_ASSET_DATA_9"))),
-                ),
-            ]
-        };
-    }
-
-    pub fn serve<C: Connection>(request: Request<C>, asset: Asset) -> HandlerResult {
-        serve_asset_data(request, AssetMetadata::derive(asset.0), asset.1)
-    }
-
-    pub fn serve_asset_data<C: Connection>(
-        request: Request<C>,
-        asset_metadata: AssetMetadata<'static>,
-        data: &'static [u8],
-    ) -> HandlerResult {
-        let mut headers = Headers::<3>::new();
-
-        if let Some(cache_control) = &asset_metadata.cache_control {
-            headers.set_cache_control(cache_control);
-        }
-
-        if let Some(content_encoding) = &asset_metadata.content_encoding {
-            headers.set_content_encoding(content_encoding);
-        }
-
-        if let Some(content_type) = &asset_metadata.content_type {
-            headers.set_content_type(content_type);
-        }
-
-        let mut response = request.into_response(200, None, headers.as_slice())?;
+#[cfg(feature = "assets-prepare")]
+pub mod prepare;
+
+/// Magic bytes at the start of an asset archive.
+///
+/// An archive packs all assets so that they can be stored in a flash partition of their own
+/// rather than embedded in the firmware image. All integers are little-endian:
+/// - Header (8 bytes): the magic, the format version (`u16`) and the number of assets (`u16`)
+/// - Index: for each asset, the offset (`u32`) and length (`u16`) of its metadata,
+///   followed by the offset (`u32`) and length (`u32`) of its data
+/// - Metadata: for each asset, its `uri`, `cache_control`, `content_encoding`, `content_type` and `etag`,
+///   each stored as a `u16` length followed by UTF-8 bytes, with a length of `ARCHIVE_NONE` denoting `None`,
+///   then the number of its `headers` (`u16`) followed by their names and values, stored the same way
+/// - Data: the (possibly compressed) asset contents
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"EFAA";
+
+pub const ARCHIVE_VERSION: u16 = 2;
+
+pub const ARCHIVE_HEADER_LEN: usize = 8;
+
+pub const ARCHIVE_INDEX_ENTRY_LEN: usize = 14;
+
+pub const ARCHIVE_NONE: u16 = u16::MAX;
+
+/// The size of the buffer in which the serving side loads the metadata of an asset from an archive.
+///
+/// Archives with the metadata of an asset exceeding it are rejected when preparing them.
+pub const METADATA_BUF_SIZE: usize = 512;
+
+/// The maximum number of additional response headers an asset can have.
+pub const MAX_ASSET_HEADERS: usize = 8;
+
+/// The additional response headers of assets, unless configured otherwise.
+///
+/// These do not restrict what a page can load, so they are safe for any UI. See `CONTENT_SECURITY_POLICY`
+/// and `STRICT_SECURITY_HEADERS` for locking the UI down further.
+pub const SECURITY_HEADERS: &[(&str, &str)] = &[
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    ("Referrer-Policy", "no-referrer"),
+];
+
+/// A `Content-Security-Policy` header allowing inline scripts and styles, as well as compiling WebAssembly,
+/// which is what the `index.html` produced by trunk needs. Everything has to come from the device itself though,
+/// so it breaks UIs loading e.g. scripts or fonts from a CDN.
+pub const CONTENT_SECURITY_POLICY: (&str, &str) = (
+    "Content-Security-Policy",
+    "default-src 'self'; script-src 'self' 'unsafe-inline' 'wasm-unsafe-eval'; style-src 'self' 'unsafe-inline'; img-src 'self' data:; frame-ancestors 'none'",
+);
+
+/// `SECURITY_HEADERS` together with `CONTENT_SECURITY_POLICY`, for UIs served entirely from the device.
+pub const STRICT_SECURITY_HEADERS: &[(&str, &str)] = &[
+    SECURITY_HEADERS[0],
+    SECURITY_HEADERS[1],
+    SECURITY_HEADERS[2],
+    CONTENT_SECURITY_POLICY,
+];
+
+/// Mapping of file extensions to their MIME types, used by `AssetMetadata::derive`.
+pub const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("bin", "application/octet-stream"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Returns the MIME type for a file extension, looking it up first in `mime_types` and then in `MIME_TYPES`.
+pub fn mime_type<'a>(extension: &str, mime_types: &[(&'a str, &'a str)]) -> Option<&'a str> {
+    mime_types
+        .iter()
+        .chain(MIME_TYPES.iter())
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, mime_type)| *mime_type)
+}
 
-        response.write_all(data)?;
+#[derive(Debug, Clone)]
+pub struct AssetMetadata<'a> {
+    pub uri: &'a str,
+    pub cache_control: Option<&'a str>,
+    pub content_encoding: Option<&'a str>,
+    pub content_type: Option<&'a str>,
+    pub etag: Option<&'a str>,
+    /// Additional response headers, at most `MAX_ASSET_HEADERS`
+    pub headers: &'a [(&'a str, &'a str)],
+}
 
-        Ok(())
+impl<'a> AssetMetadata<'a> {
+    pub fn derive(uri: &str) -> AssetMetadata<'_> {
+        Self::derive_with_mime_types(uri, &[])
     }
 
-    #[cfg(feature = "nightly")]
-    pub mod asynch {
-        use core::future::Future;
-
-        use embedded_svc::http::server::asynch::{Connection, Handler, HandlerResult, Request};
-        use embedded_svc::utils::http::Headers;
-
-        use embedded_svc::io::asynch::Write;
-
-        pub use super::{Asset, AssetMetadata};
-
-        pub struct AssetHandler(AssetMetadata<'static>, &'static [u8]);
-
-        impl AssetHandler {
-            pub const fn new(metadata: AssetMetadata<'static>, data: &'static [u8]) -> Self {
-                Self(metadata, data)
-            }
-
-            pub fn from_asset(asset: Asset) -> Self {
-                Self(AssetMetadata::derive(asset.0), asset.1)
-            }
-        }
-
-        impl<C: Connection> Handler<C> for AssetHandler {
-            type HandleFuture<'a> = impl Future<Output = HandlerResult> where Self: 'a, C: 'a;
-
-            fn handle<'a>(&'a self, connection: &'a mut C) -> Self::HandleFuture<'a> {
-                async move { serve_asset_data(Request::wrap(connection), self.0.clone(), &self.1).await }
-            }
-        }
-
-        pub async fn serve<C: Connection>(request: Request<C>, asset: Asset) -> HandlerResult {
-            serve_asset_data(request, AssetMetadata::derive(asset.0), asset.1).await
-        }
-
-        pub async fn serve_asset_data<C: Connection>(
-            request: Request<C>,
-            asset_metadata: AssetMetadata<'static>,
-            data: &'static [u8],
-        ) -> HandlerResult {
-            let mut headers = Headers::<3>::new();
-
-            if let Some(cache_control) = &asset_metadata.cache_control {
-                headers.set_cache_control(cache_control);
-            }
-
-            if let Some(content_encoding) = &asset_metadata.content_encoding {
-                headers.set_content_encoding(content_encoding);
-            }
-
-            if let Some(content_type) = &asset_metadata.content_type {
-                headers.set_content_type(content_type);
-            }
-
-            let mut response = request.into_response(200, None, headers.as_slice()).await?;
+    /// Same as `derive`, but with `mime_types` overriding or extending the built-in `MIME_TYPES` table.
+    pub fn derive_with_mime_types<'b>(
+        uri: &'b str,
+        mime_types: &[(&'b str, &'b str)],
+    ) -> AssetMetadata<'b> {
+        let file_name = uri.rsplit('/').next().unwrap_or(uri);
 
-            response.write_all(data).await?;
+        let mut split = file_name.split('.');
 
-            Ok(())
-        }
-    }
+        let suffix = if file_name.contains('.') {
+            split.next_back().unwrap_or("")
+        } else {
+            ""
+        };
 
-    #[derive(Debug, Clone)]
-    pub struct AssetMetadata<'a> {
-        pub uri: &'a str,
-        pub cache_control: Option<&'a str>,
-        pub content_encoding: Option<&'a str>,
-        pub content_type: Option<&'a str>,
-    }
+        let (uri, content_encoding) = if suffix.eq_ignore_ascii_case("gz") {
+            (&uri[..uri.len() - 3], Some("gzip"))
+        } else if suffix.eq_ignore_ascii_case("br") {
+            (&uri[..uri.len() - 3], Some("br"))
+        } else {
+            (uri, None)
+        };
 
-    impl<'a> AssetMetadata<'a> {
-        pub fn derive(uri: &str) -> AssetMetadata<'_> {
-            let mut split = uri.split('.');
+        let (uri, cache_control) = if uri.eq_ignore_ascii_case("/index.html") {
+            ("/", "no-cache")
+        } else {
+            (uri, "public, max-age=31536000")
+        };
 
+        let suffix = if content_encoding.is_some() {
             let suffix = split.next_back().unwrap_or("");
 
-            let (uri, content_encoding) = if suffix.eq_ignore_ascii_case("gz") {
-                (&uri[..uri.len() - 3], Some("gzip"))
-            } else {
-                (uri, None)
-            };
-
-            let (uri, cache_control) = if uri.eq_ignore_ascii_case("/index.html") {
-                ("/", "no-store")
-            } else {
-                (uri, "public, max-age=31536000")
-            };
-
-            let suffix = if content_encoding.is_some() {
-                split.next_back().unwrap_or("")
-            } else {
+            // A file name with no extension besides the encoding one, e.g. `LICENSE.gz`
+            if split.next().is_some() {
                 suffix
-            };
-
-            let content_type = if suffix.eq_ignore_ascii_case("html") {
-                Some("text/html")
-            } else if suffix.eq_ignore_ascii_case("css") {
-                Some("text/css")
-            } else if suffix.eq_ignore_ascii_case("js") {
-                Some("text/javascript")
-            } else if suffix.eq_ignore_ascii_case("wasm") {
-                Some("application/wasm")
             } else {
-                None
-            };
-
-            AssetMetadata {
-                uri,
-                cache_control: Some(cache_control),
-                content_encoding: content_encoding,
-                content_type: content_type,
+                ""
             }
-        }
-    }
-}
-
-#[cfg(feature = "assets-prepare")]
-pub mod prepare {
-    use std::{
-        env, fs, io,
-        iter::repeat,
-        path::{Path, PathBuf},
-    };
-
-    use anyhow;
-    use flate2::{write::GzEncoder, Compression};
-
-    pub fn run(module: impl AsRef<str>, assets_dir: impl AsRef<Path>) -> anyhow::Result<()> {
-        let module = module.as_ref();
-        let assets_dir = assets_dir.as_ref();
-
-        let output_dir = PathBuf::new()
-            .join(env::var_os("OUT_DIR")
-                .ok_or_else(|| anyhow::anyhow!("OUT_DIR variable is not defined. You should call this code from a Cargo `build.rs` script"))?)
-            .join("edge_frame_assets")
-            .join(module);
-
-        let output_files = compress(assets_dir, &output_dir, |path| {
-            println!("cargo:rerun-if-changed={}", path.display())
-        })?;
-
-        let output_files = if output_files.len() > super::MAX_ASSETS {
-            anyhow::bail!(
-                "Maximum number of supported assets is {}",
-                super::MAX_ASSETS
-            );
-        } else if output_files.len() < super::MAX_ASSETS {
-            let empty_file = output_dir.join("__empty__");
-
-            fs::File::create(&empty_file)?;
-
-            let len = output_files.len();
-
-            output_files
-                .into_iter()
-                .chain(repeat(empty_file).take(super::MAX_ASSETS - len))
-                .collect::<Vec<_>>()
         } else {
-            output_files
+            suffix
         };
 
-        for (index, output_file) in output_files.iter().enumerate() {
-            let file_name = output_file.file_name().unwrap().to_str().unwrap();
-
-            if file_name == "__empty__" {
-                println!("cargo:rustc-env={}_EDGE_FRAME_ASSET_URI_{}=", module, index,);
-            } else {
-                println!(
-                    "cargo:rustc-env={}_EDGE_FRAME_ASSET_URI_{}=/{}",
-                    module,
-                    index,
-                    output_file.file_name().unwrap().to_str().unwrap()
-                );
-            }
+        let content_type = mime_type(suffix, mime_types);
 
-            println!(
-                "cargo:rustc-env={}_EDGE_FRAME_ASSET_DATA_{}={}",
-                module,
-                index,
-                output_file.display()
-            );
+        AssetMetadata {
+            uri,
+            cache_control: Some(cache_control),
+            content_encoding,
+            content_type,
+            etag: None,
+            headers: SECURITY_HEADERS,
         }
-
-        Ok(())
     }
+}
 
-    pub fn compress(
-        assets_dir: impl AsRef<Path>,
-        output_dir: impl AsRef<Path>,
-        track: impl Fn(&Path),
-    ) -> anyhow::Result<Vec<PathBuf>> {
-        let assets_dir = assets_dir.as_ref();
-        let output_dir = output_dir.as_ref();
-
-        let output_files = fs::read_dir(assets_dir)?
-            .filter_map(|file| file.ok())
-            .filter(|file| file.metadata().map(|md| md.is_file()).unwrap_or(false))
-            .map(|file| {
-                track(&file.path());
-
-                let output_file =
-                    output_dir.join(format!("{}.gz", file.file_name().to_str().unwrap()));
-
-                track(&output_file);
-
-                fs::create_dir_all(output_dir).unwrap();
-
-                io::copy(
-                    &mut fs::File::open(file.path()).unwrap(),
-                    &mut GzEncoder::new(
-                        fs::File::create(&output_file).unwrap(),
-                        Compression::best(),
-                    ),
-                )
-                .unwrap();
-
-                output_file
-            })
-            .collect::<Vec<_>>();
+/// An overview of the assets produced by `prepare`, available to firmware code via `assets_manifest!`,
+/// e.g. for reporting the version of the UI bundle.
+#[derive(Debug, Clone)]
+pub struct Manifest<'a> {
+    /// A hash over all assets, which changes whenever any of them does
+    pub version: &'a str,
+    pub assets: &'a [ManifestEntry<'a>],
+}
 
-        Ok(output_files)
-    }
+#[derive(Debug, Clone)]
+pub struct ManifestEntry<'a> {
+    pub uri: &'a str,
+    pub content_encoding: Option<&'a str>,
+    /// The size of the original asset file
+    pub size: usize,
+    /// The size of the asset after minification, if it got minified
+    pub minified_size: Option<usize>,
+    /// The size of the asset as embedded, i.e. after encoding
+    pub compressed_size: usize,
+    /// The SHA-256 hex digest of the asset as embedded
+    pub hash: &'a str,
 }
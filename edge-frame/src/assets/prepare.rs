@@ -0,0 +1,1816 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{self, Context};
+use brotli::enc::BrotliEncoderParams;
+use flate2::{write::GzEncoder, Compression};
+use glob::{MatchOptions, Pattern};
+use sha2::{Digest, Sha256};
+
+use super::{
+    AssetMetadata, ARCHIVE_HEADER_LEN, ARCHIVE_INDEX_ENTRY_LEN, ARCHIVE_MAGIC, ARCHIVE_NONE,
+    ARCHIVE_VERSION, MAX_ASSET_HEADERS, METADATA_BUF_SIZE, SECURITY_HEADERS,
+};
+
+mod minify;
+
+pub fn run(module: impl AsRef<str>, assets_dir: impl AsRef<Path>) -> anyhow::Result<()> {
+    Builder::new(module, assets_dir).run()
+}
+
+/// The encodings in which each asset can be embedded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// The asset is embedded as-is
+    Identity,
+    /// The asset is gzip-compressed and gets a `.gz` suffix
+    Gzip,
+    /// The asset is brotli-compressed and gets a `.br` suffix
+    Brotli,
+}
+
+impl Encoding {
+    fn file_name(&self, file_name: &str) -> String {
+        match self {
+            Self::Identity => file_name.to_string(),
+            Self::Gzip => format!("{}.gz", file_name),
+            Self::Brotli => format!("{}.br", file_name),
+        }
+    }
+}
+
+/// The kinds of text assets which can be minified, by stripping their comments and redundant whitespace.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Minify {
+    /// `.html` and `.htm` assets, including their inline styles and scripts if `Css` and `Js` are enabled as well
+    Html,
+    /// `.css` assets
+    Css,
+    /// `.js` and `.mjs` assets
+    Js,
+}
+
+impl Minify {
+    fn of(file: &str) -> Option<Self> {
+        let extension = file.rsplit_once('.')?.1.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "html" | "htm" => Some(Self::Html),
+            "css" => Some(Self::Css),
+            "js" | "mjs" => Some(Self::Js),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, text: &str, minify: &[Minify]) -> String {
+        match self {
+            Self::Html => minify::html(
+                text,
+                minify.contains(&Self::Css),
+                minify.contains(&Self::Js),
+            ),
+            Self::Css => minify::css(text),
+            Self::Js => minify::js(text),
+        }
+    }
+}
+
+/// A command building the frontend crate, whose output gets embedded.
+#[derive(Clone, Debug)]
+struct Frontend {
+    dir: PathBuf,
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Builder {
+    module: String,
+    assets_dir: PathBuf,
+    encodings: Vec<Encoding>,
+    mime_types: Vec<(String, String)>,
+    cache_controls: Vec<(String, String)>,
+    headers: Vec<(String, Vec<(String, String)>)>,
+    archive: Option<PathBuf>,
+    total_budget: Option<u64>,
+    asset_budget: Option<u64>,
+    budget_warning: Option<u8>,
+    frontend: Option<Frontend>,
+    hashed_file_names: bool,
+    minify: Vec<Minify>,
+}
+
+impl Builder {
+    pub fn new(module: impl AsRef<str>, assets_dir: impl AsRef<Path>) -> Self {
+        Self {
+            module: module.as_ref().to_string(),
+            assets_dir: assets_dir.as_ref().to_path_buf(),
+            encodings: vec![Encoding::Gzip],
+            mime_types: Vec::new(),
+            cache_controls: Vec::new(),
+            headers: Vec::new(),
+            archive: None,
+            total_budget: None,
+            asset_budget: None,
+            budget_warning: None,
+            frontend: None,
+            hashed_file_names: false,
+            minify: Vec::new(),
+        }
+    }
+
+    /// Sets the encoded variants to embed for each asset, in order of preference.
+    ///
+    /// When serving, the first variant accepted by the client (as per its `Accept-Encoding` header) is used.
+    /// E.g. `&[Encoding::Gzip, Encoding::Identity]` keeps an uncompressed fallback for clients not supporting gzip,
+    /// while `&[Encoding::Brotli, Encoding::Gzip]` saves flash on brotli-capable clients yet falls back to gzip
+    /// for the rest. `&[Encoding::Identity]` disables compression altogether.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Sets the MIME type of assets with the given file extension, overriding or extending
+    /// the built-in `MIME_TYPES` table.
+    pub fn mime_type(mut self, extension: impl AsRef<str>, mime_type: impl AsRef<str>) -> Self {
+        self.mime_types.push((
+            extension.as_ref().to_string(),
+            mime_type.as_ref().to_string(),
+        ));
+        self
+    }
+
+    /// Sets the `Cache-Control` header of the assets whose path matches `glob`, e.g. `/config.json` or `/snippets/**`.
+    ///
+    /// Rules are evaluated in the order in which they were added and the first matching one wins.
+    /// Assets not matching any rule get `no-cache` for `/index.html` and a one-year `max-age` for everything else,
+    /// which is only correct for assets whose file names change with their content. With `hashed_file_names`,
+    /// the assets which do not get renamed get `no-cache` instead.
+    pub fn cache_control(mut self, glob: impl AsRef<str>, cache_control: impl AsRef<str>) -> Self {
+        self.cache_controls.push((
+            glob.as_ref().to_string(),
+            cache_control.as_ref().to_string(),
+        ));
+        self
+    }
+
+    /// Sets the additional response headers of the assets whose path matches `glob`, replacing the default
+    /// `SECURITY_HEADERS`.
+    ///
+    /// The defaults do not include a `Content-Security-Policy`, as the one fitting the UI depends on what it loads.
+    /// E.g. `.headers("/**", STRICT_SECURITY_HEADERS)` adds `CONTENT_SECURITY_POLICY`, which only allows
+    /// content from the device itself, and thus breaks pages loading scripts or fonts from a CDN
+    /// (like the Font Awesome kit of the kitchen sink). Such pages need a policy of their own, listing the CDN.
+    ///
+    /// Rules are matched the same way as the `cache_control` ones. An asset can have at most `MAX_ASSET_HEADERS`
+    /// additional headers, and an empty `headers` slice removes them altogether.
+    pub fn headers(mut self, glob: impl AsRef<str>, headers: &[(&str, &str)]) -> Self {
+        self.headers.push((
+            glob.as_ref().to_string(),
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        ));
+        self
+    }
+
+    /// Additionally packs all assets into an archive at `path`, to be flashed into a partition of its own
+    /// and served with `serve::Archive` rather than embedded in the firmware with `assets!`.
+    pub fn archive(mut self, path: impl AsRef<Path>) -> Self {
+        self.archive = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Fails the build when the embedded assets take more than `bytes` in total, i.e. across all encoded variants.
+    pub fn total_budget(mut self, bytes: u64) -> Self {
+        self.total_budget = Some(bytes);
+        self
+    }
+
+    /// Fails the build when any embedded asset variant takes more than `bytes`.
+    pub fn asset_budget(mut self, bytes: u64) -> Self {
+        self.asset_budget = Some(bytes);
+        self
+    }
+
+    /// Emits a `cargo:warning` when the embedded assets take more than `percent` of either budget.
+    pub fn budget_warning(mut self, percent: u8) -> Self {
+        self.budget_warning = Some(percent);
+        self
+    }
+
+    /// Runs `program` with `args` in the frontend crate at `ui_dir` before preparing the assets,
+    /// so that the assets directory never holds a stale bundle.
+    ///
+    /// The sources of the frontend crate (i.e. everything in `ui_dir` but its `target` directory,
+    /// hidden files and the assets directory itself) are tracked, so that the firmware is rebuilt
+    /// whenever the UI changes.
+    pub fn frontend_build(
+        mut self,
+        ui_dir: impl AsRef<Path>,
+        program: impl AsRef<Path>,
+        args: &[&str],
+    ) -> Self {
+        self.frontend = Some(Frontend {
+            dir: ui_dir.as_ref().to_path_buf(),
+            program: program.as_ref().to_path_buf(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        });
+        self
+    }
+
+    /// Same as `frontend_build`, with `trunk build --release` as the build command.
+    ///
+    /// `trunk` is either the path to a local trunk binary, or just `"trunk"` to use the one in `PATH`.
+    /// The assets directory would then usually be `dist` in `ui_dir`.
+    pub fn trunk(self, ui_dir: impl AsRef<Path>, trunk: impl AsRef<Path>) -> Self {
+        self.frontend_build(ui_dir, trunk, &["build", "--release"])
+    }
+
+    /// Renames the assets referenced from `index.html` to include a short hash of their content
+    /// (e.g. `/app.js` becomes `/app-1a2b3c4d.js`) and rewrites these references accordingly,
+    /// which makes the default one-year `max-age` of these assets correct.
+    ///
+    /// A reference is a path to the asset in quotes, either absolute (`"/app.js"`) or relative (`"app.js"`, `'./app.js'`),
+    /// so it covers `script` and `link` tags as well as the path passed to the `init` function of wasm-bindgen.
+    /// Bare file names without an extension only count as absolute (`"/LICENSE"`) or `./`-prefixed (`"./LICENSE"`) paths,
+    /// as e.g. a plain `"LICENSE"` is too likely to be some unrelated string.
+    /// Assets which are not referenced from `index.html` keep their names, and thus get `no-cache` by default,
+    /// as a one-year `max-age` would keep clients on a stale version of them. This includes the files
+    /// imported by the wasm-bindgen glue code, e.g. `snippets/**/inline0.js`.
+    /// Note that `cache_control` rules are matched against the renamed paths.
+    pub fn hashed_file_names(mut self, hashed_file_names: bool) -> Self {
+        self.hashed_file_names = hashed_file_names;
+        self
+    }
+
+    /// Minifies the assets of the given kinds before encoding them.
+    ///
+    /// The minification is conservative, i.e. it only strips comments and redundant whitespace.
+    /// The size of each minified asset is reported in the manifest.
+    pub fn minify(mut self, minify: &[Minify]) -> Self {
+        self.minify = minify.to_vec();
+        self
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        if self.encodings.is_empty() {
+            anyhow::bail!("At least one asset encoding should be specified");
+        }
+
+        let module = self.module.as_str();
+        let assets_dir = self.assets_dir.as_path();
+
+        if let Some(frontend) = &self.frontend {
+            frontend.build(assets_dir)?;
+        }
+
+        let mime_types = self
+            .mime_types
+            .iter()
+            .rev()
+            .map(|(extension, mime_type)| (extension.as_str(), mime_type.as_str()))
+            .collect::<Vec<_>>();
+
+        let cache_controls = self
+            .cache_controls
+            .iter()
+            .map(|(glob, cache_control)| {
+                Pattern::new(glob)
+                    .map(|pattern| (pattern, cache_control.as_str()))
+                    .map_err(|err| anyhow::anyhow!("Invalid glob pattern `{}`: {}", glob, err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let headers = self
+            .headers
+            .iter()
+            .map(|(glob, headers)| {
+                if headers.len() > MAX_ASSET_HEADERS {
+                    anyhow::bail!(
+                        "Too many headers for `{}`: {}, the maximum is {}",
+                        glob,
+                        headers.len(),
+                        MAX_ASSET_HEADERS
+                    );
+                }
+
+                let pattern = Pattern::new(glob)
+                    .map_err(|err| anyhow::anyhow!("Invalid glob pattern `{}`: {}", glob, err))?;
+
+                Ok((
+                    pattern,
+                    headers
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.as_str()))
+                        .collect::<Vec<_>>(),
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let out_dir = PathBuf::new()
+            .join(env::var_os("OUT_DIR")
+                .ok_or_else(|| anyhow::anyhow!("OUT_DIR variable is not defined. You should call this code from a Cargo `build.rs` script"))?);
+
+        let output_dir = out_dir.join("edge_frame_assets").join(module);
+
+        let track = |path: &Path| {
+            // With a frontend build, the assets are its output rather than sources, and their
+            // modification during the build script run would otherwise trigger endless rebuilds
+            if self.frontend.is_none() {
+                println!("cargo:rerun-if-changed={}", path.display())
+            }
+        };
+
+        let (staged_files, output_files) = if self.hashed_file_names || !self.minify.is_empty() {
+            let staging_dir = out_dir.join("edge_frame_staging").join(module);
+
+            let staged_files = stage(
+                assets_dir,
+                &staging_dir,
+                self.hashed_file_names,
+                &self.minify,
+                &track,
+            )?;
+
+            let output_files = compress(&staging_dir, &output_dir, &self.encodings, |path| {
+                if !path.starts_with(&staging_dir) {
+                    track(path);
+                }
+            })?;
+
+            (staged_files, output_files)
+        } else {
+            let output_files = compress(assets_dir, &output_dir, &self.encodings, track)?;
+
+            (HashMap::new(), output_files)
+        };
+
+        let mut entries = Vec::new();
+
+        for output_file in output_files {
+            // Components are UTF-8, as `compress` rejects any other file names
+            let uri = output_file
+                .strip_prefix(&output_dir)?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let uri = format!("/{}", uri);
+
+            let data = fs::read(&output_file)
+                .with_context(|| format!("Cannot read `{}`", output_file.display()))?;
+
+            let asset_metadata = AssetMetadata::derive_with_mime_types(&uri, &mime_types);
+
+            let path = asset_path(&uri, &asset_metadata);
+
+            let staged_file = staged_files.get(path.trim_start_matches('/'));
+
+            let cache_control = find_rule(&cache_controls, path)
+                .copied()
+                .or(match staged_file {
+                    // The contents of an asset which keeps its name can change without its URI changing
+                    Some(staged_file) if self.hashed_file_names && !staged_file.hashed => {
+                        Some("no-cache")
+                    }
+                    _ => asset_metadata.cache_control,
+                });
+
+            let asset_headers = find_rule(&headers, path)
+                .map(Vec::as_slice)
+                .unwrap_or(SECURITY_HEADERS);
+
+            let (size, minified_size) = match staged_file {
+                Some(staged_file) => (staged_file.size, staged_file.minified_size),
+                None => {
+                    let input_file = assets_dir.join(path.trim_start_matches('/'));
+
+                    let size = fs::metadata(&input_file)
+                        .with_context(|| {
+                            format!("Cannot read the metadata of `{}`", input_file.display())
+                        })?
+                        .len();
+
+                    (size, None)
+                }
+            };
+
+            entries.push(Entry {
+                uri: asset_metadata.uri.to_string(),
+                cache_control: cache_control.map(str::to_string),
+                content_encoding: asset_metadata.content_encoding.map(str::to_string),
+                content_type: asset_metadata.content_type.map(str::to_string),
+                etag: etag(&data),
+                headers: asset_headers,
+                hash: hash(&data),
+                size,
+                minified_size,
+                compressed_size: data.len() as u64,
+                data_file: output_file,
+            });
+        }
+
+        fs::create_dir_all(&output_dir).with_context(|| {
+            format!("Cannot create output directory `{}`", output_dir.display())
+        })?;
+
+        let mut assets = create_file(&output_dir.join("assets.rs"))?;
+
+        writeln!(assets, "&[")?;
+
+        for entry in &entries {
+            write_asset(&mut assets, &entry.metadata(), &entry.data_file)?;
+        }
+
+        writeln!(assets, "]")?;
+
+        let version = version(&entries);
+
+        write_manifest(
+            create_file(&output_dir.join("manifest.rs"))?,
+            &version,
+            &entries,
+        )?;
+
+        let manifest = serde_json::json!({
+            "module": module,
+            "version": version,
+            "assets": entries
+                .iter()
+                .map(|entry| serde_json::json!({
+                    "uri": entry.uri,
+                    "encoding": entry.content_encoding.as_deref().unwrap_or("identity"),
+                    "size": entry.size,
+                    "minified_size": entry.minified_size,
+                    "compressed_size": entry.compressed_size,
+                    "hash": entry.hash,
+                }))
+                .collect::<Vec<_>>(),
+        });
+
+        let manifest_file = output_dir.join("manifest.json");
+
+        fs::write(&manifest_file, serde_json::to_string_pretty(&manifest)?)
+            .with_context(|| format!("Cannot write `{}`", manifest_file.display()))?;
+
+        self.check_budgets(&entries)?;
+
+        if let Some(archive) = &self.archive {
+            write_archive(create_file(archive)?, &entries)
+                .with_context(|| format!("Cannot write archive `{}`", archive.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn check_budgets(&self, entries: &[Entry]) -> anyhow::Result<()> {
+        let total = entries
+            .iter()
+            .map(|entry| entry.compressed_size)
+            .sum::<u64>();
+
+        let exceeds = |size: u64, budget: u64, percent: u64| size * 100 > budget * percent;
+
+        let mut violations = Vec::new();
+
+        if let Some(budget) = self.total_budget {
+            if exceeds(total, budget, 100) {
+                violations.push(format!(
+                    "Total of {} bytes exceeds the budget of {} bytes",
+                    total, budget
+                ));
+            } else if let Some(percent) = self.budget_warning {
+                if exceeds(total, budget, percent as _) {
+                    println!(
+                        "cargo:warning=Assets of module `{}` take {} bytes, above {}% of the budget of {} bytes",
+                        self.module, total, percent, budget
+                    );
+                }
+            }
+        }
+
+        if let Some(budget) = self.asset_budget {
+            for entry in entries {
+                if exceeds(entry.compressed_size, budget, 100) {
+                    violations.push(format!(
+                        "{} takes {} bytes, exceeding the per-asset budget of {} bytes",
+                        entry.name(),
+                        entry.compressed_size,
+                        budget
+                    ));
+                } else if let Some(percent) = self.budget_warning {
+                    if exceeds(entry.compressed_size, budget, percent as _) {
+                        println!(
+                            "cargo:warning={} takes {} bytes, above {}% of the per-asset budget of {} bytes",
+                            entry.name(),
+                            entry.compressed_size,
+                            percent,
+                            budget
+                        );
+                    }
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        let mut report = format!(
+            "Assets of module `{}` exceed their size budget:\n",
+            self.module
+        );
+
+        for violation in &violations {
+            report.push_str(&format!("- {}\n", violation));
+        }
+
+        report.push_str("\nAssets by embedded size (original size):\n");
+
+        let mut entries = entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.compressed_size));
+
+        for entry in entries {
+            report.push_str(&format!(
+                "{:>10} ({:>10})  {}\n",
+                entry.compressed_size,
+                entry.size,
+                entry.name()
+            ));
+        }
+
+        report.push_str(&format!("{:>10}               total", total));
+
+        Err(anyhow::anyhow!(report))
+    }
+}
+
+impl Frontend {
+    fn build(&self, assets_dir: &Path) -> anyhow::Result<()> {
+        let excluded = fs::canonicalize(assets_dir).ok();
+
+        let mut entries = fs::read_dir(&self.dir)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Cannot read frontend directory `{}`", self.dir.display()))?;
+
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name();
+
+            if name == "target" || name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            if excluded.is_some() && fs::canonicalize(entry.path()).ok() == excluded {
+                continue;
+            }
+
+            println!("cargo:rerun-if-changed={}", entry.path().display());
+        }
+
+        let mut command = Command::new(&self.program);
+
+        command.args(&self.args).current_dir(&self.dir);
+
+        // Do not let the firmware build's target, toolchain and flags leak into the frontend build
+        for (key, _) in env::vars_os() {
+            let key = key.to_string_lossy();
+
+            if (key.starts_with("CARGO_") && key != "CARGO_HOME")
+                || key.starts_with("RUSTC")
+                || key.starts_with("RUSTDOC")
+                || [
+                    "CARGO",
+                    "RUSTFLAGS",
+                    "RUSTUP_TOOLCHAIN",
+                    "TARGET",
+                    "HOST",
+                    "OUT_DIR",
+                    "PROFILE",
+                    "OPT_LEVEL",
+                    "DEBUG",
+                    "NUM_JOBS",
+                ]
+                .contains(&key.as_ref())
+            {
+                command.env_remove(key.as_ref());
+            }
+        }
+
+        let status = command.status().with_context(|| {
+            format!(
+                "Cannot run frontend build command `{}` in `{}`",
+                self.program.display(),
+                self.dir.display()
+            )
+        })?;
+
+        if !status.success() {
+            anyhow::bail!(
+                "Frontend build command `{} {}` failed in `{}` with {}",
+                self.program.display(),
+                self.args.join(" "),
+                self.dir.display(),
+                status
+            );
+        }
+
+        if !assets_dir.join("index.html").is_file() {
+            anyhow::bail!(
+                "Frontend build did not produce `index.html` in the assets directory `{}`",
+                assets_dir.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies the assets into `staging_dir`, renaming the ones referenced from `index.html` as per `Builder::hashed_file_names`
+/// and minifying them as per `Builder::minify`.
+///
+/// Returns the staged assets by their staged paths.
+fn stage(
+    assets_dir: &Path,
+    staging_dir: &Path,
+    hashed_file_names: bool,
+    minify: &[Minify],
+    track: &impl Fn(&Path),
+) -> anyhow::Result<HashMap<String, StagedFile>> {
+    let mut files = Vec::new();
+
+    list_files(assets_dir, "", track, &mut files)?;
+
+    let index_file = assets_dir.join("index.html");
+
+    let mut index = if hashed_file_names && index_file.is_file() {
+        Some(
+            fs::read_to_string(&index_file)
+                .with_context(|| format!("Cannot read `{}`", index_file.display()))?,
+        )
+    } else {
+        None
+    };
+
+    if staging_dir.exists() {
+        fs::remove_dir_all(staging_dir).with_context(|| {
+            format!("Cannot clean staging directory `{}`", staging_dir.display())
+        })?;
+    }
+
+    fs::create_dir_all(staging_dir).with_context(|| {
+        format!(
+            "Cannot create staging directory `{}`",
+            staging_dir.display()
+        )
+    })?;
+
+    let mut staged_files = HashMap::new();
+
+    // `index.html` goes last, once all references in it are rewritten
+    for file in files.iter().filter(|file| *file != "index.html") {
+        let input_file = assets_dir.join(file);
+
+        let data = fs::read(&input_file)
+            .with_context(|| format!("Cannot read `{}`", input_file.display()))?;
+
+        let mut staged = file.clone();
+        let mut hashed = false;
+
+        if let Some(index) = index.as_mut() {
+            let references = ["\"", "'"]
+                .iter()
+                .flat_map(|quote| ["/", "./", ""].iter().map(move |prefix| (*quote, *prefix)))
+                // A bare file name without extension only counts with a prefix, see `Builder::hashed_file_names`
+                .filter(|(_, prefix)| !prefix.is_empty() || file.contains(['.', '/']))
+                .filter(|(quote, prefix)| {
+                    index.contains(&format!("{}{}{}{}", quote, prefix, file, quote))
+                })
+                .collect::<Vec<_>>();
+
+            if !references.is_empty() {
+                staged = hashed_file_name(file, &hash(&data)[..8]);
+                hashed = true;
+
+                for (quote, prefix) in references {
+                    *index = index.replace(
+                        &format!("{}{}{}{}", quote, prefix, file, quote),
+                        &format!("{}{}{}{}", quote, prefix, staged, quote),
+                    );
+                }
+            }
+        }
+
+        let size = data.len() as u64;
+        let minified_size = stage_file(&staging_dir.join(&staged), data, minify)?;
+
+        staged_files.insert(
+            staged,
+            StagedFile {
+                size,
+                minified_size,
+                hashed,
+            },
+        );
+    }
+
+    if index_file.is_file() {
+        let size = fs::metadata(&index_file)
+            .with_context(|| format!("Cannot read the metadata of `{}`", index_file.display()))?
+            .len();
+
+        let data = match index {
+            Some(index) => index.into_bytes(),
+            None => fs::read(&index_file)
+                .with_context(|| format!("Cannot read `{}`", index_file.display()))?,
+        };
+
+        let minified_size = stage_file(&staging_dir.join("index.html"), data, minify)?;
+
+        staged_files.insert(
+            "index.html".to_string(),
+            StagedFile {
+                size,
+                minified_size,
+                hashed: false,
+            },
+        );
+    }
+
+    Ok(staged_files)
+}
+
+/// The path of the original asset of an encoded variant, i.e. its URI without the encoding suffix.
+fn asset_path<'a>(uri: &'a str, asset_metadata: &AssetMetadata) -> &'a str {
+    match asset_metadata.content_encoding {
+        Some(_) => uri.rsplit_once('.').map(|(path, _)| path).unwrap_or(uri),
+        None => uri,
+    }
+}
+
+/// Returns the value of the first of the `cache_control` or `headers` rules matching the asset `path`.
+fn find_rule<'r, T>(rules: &'r [(Pattern, T)], path: &str) -> Option<&'r T> {
+    let match_options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    rules
+        .iter()
+        .find(|(pattern, _)| pattern.matches_with(path, match_options))
+        .map(|(_, value)| value)
+}
+
+/// An asset as copied into the staging directory by `stage`.
+struct StagedFile {
+    /// The size of the original asset
+    size: u64,
+    /// The size of the asset once minified, if it got minified
+    minified_size: Option<u64>,
+    /// Whether the asset got renamed to include a hash of its content
+    hashed: bool,
+}
+
+/// Writes an asset into the staging directory, minifying it if its kind is in `minify`.
+///
+/// Returns the minified size of the asset if it got minified.
+fn stage_file(staged_file: &Path, data: Vec<u8>, minify: &[Minify]) -> anyhow::Result<Option<u64>> {
+    let kind = staged_file
+        .to_str()
+        .and_then(Minify::of)
+        .filter(|kind| minify.contains(kind));
+
+    let (data, minified_size) = match kind {
+        Some(kind) => match String::from_utf8(data) {
+            Ok(text) => {
+                let minified = kind.apply(&text, minify).into_bytes();
+                let minified_size = minified.len() as u64;
+
+                (minified, Some(minified_size))
+            }
+            // Assets which are not UTF-8 are left as they are
+            Err(err) => (err.into_bytes(), None),
+        },
+        None => (data, None),
+    };
+
+    if let Some(parent) = staged_file.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Cannot create directory `{}`", parent.display()))?;
+    }
+
+    fs::write(staged_file, data)
+        .with_context(|| format!("Cannot write `{}`", staged_file.display()))?;
+
+    Ok(minified_size)
+}
+
+/// Lists the files in `dir` recursively, as `/`-separated paths relative to the assets directory.
+fn list_files(
+    dir: &Path,
+    prefix: &str,
+    track: &impl Fn(&Path),
+    files: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    track(dir);
+
+    let entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Cannot read assets directory `{}`", dir.display()))?;
+
+    for entry in entries {
+        let path = entry.path();
+
+        let file_name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("Asset `{}` has a non-UTF-8 file name", path.display()))?;
+
+        let file = format!("{}{}", prefix, file_name);
+
+        let metadata = fs::metadata(&path)
+            .with_context(|| format!("Cannot read the metadata of asset `{}`", path.display()))?;
+
+        if metadata.is_dir() {
+            list_files(&path, &format!("{}/", file), track, files)?;
+        } else if metadata.is_file() {
+            track(&path);
+
+            files.push(file);
+        }
+    }
+
+    Ok(())
+}
+
+/// Inserts `hash` into the file name of `file`, before its extension, if any.
+fn hashed_file_name(file: &str, hash: &str) -> String {
+    let (dir, file_name) = match file.rsplit_once('/') {
+        Some((dir, file_name)) => (format!("{}/", dir), file_name),
+        None => (String::new(), file),
+    };
+
+    match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}{}-{}.{}", dir, stem, hash, extension)
+        }
+        _ => format!("{}{}-{}", dir, file_name, hash),
+    }
+}
+
+fn create_file(path: &Path) -> anyhow::Result<fs::File> {
+    fs::File::create(path).with_context(|| format!("Cannot create `{}`", path.display()))
+}
+
+/// An asset as produced by the build, together with its final metadata.
+struct Entry<'a> {
+    uri: String,
+    cache_control: Option<String>,
+    content_encoding: Option<String>,
+    content_type: Option<String>,
+    etag: String,
+    headers: &'a [(&'a str, &'a str)],
+    hash: String,
+    size: u64,
+    minified_size: Option<u64>,
+    compressed_size: u64,
+    data_file: PathBuf,
+}
+
+impl<'a> Entry<'a> {
+    /// The URI and encoding of the asset variant, for reporting.
+    fn name(&self) -> String {
+        format!(
+            "`{}` ({})",
+            self.uri,
+            self.content_encoding.as_deref().unwrap_or("identity")
+        )
+    }
+
+    fn metadata(&self) -> AssetMetadata<'_> {
+        AssetMetadata {
+            uri: &self.uri,
+            cache_control: self.cache_control.as_deref(),
+            content_encoding: self.content_encoding.as_deref(),
+            content_type: self.content_type.as_deref(),
+            etag: Some(&self.etag),
+            headers: self.headers,
+        }
+    }
+}
+
+/// Computes a strong entity tag (a quoted, truncated SHA-256 hex digest) for the asset data.
+pub fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", &hash(data)[..16])
+}
+
+/// Computes the SHA-256 hex digest of the asset data.
+pub fn hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Computes the version of the whole bundle as a truncated hash over the URIs, encodings and hashes of all assets.
+fn version(entries: &[Entry]) -> String {
+    let mut digest = Sha256::new();
+
+    for entry in entries {
+        digest.update(entry.uri.as_bytes());
+        digest.update([0]);
+        digest.update(entry.content_encoding.as_deref().unwrap_or("").as_bytes());
+        digest.update([0]);
+        digest.update(entry.hash.as_bytes());
+        digest.update([0]);
+    }
+
+    digest.finalize()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn write_manifest(mut write: impl Write, version: &str, entries: &[Entry]) -> anyhow::Result<()> {
+    writeln!(write, "Manifest {{")?;
+    writeln!(write, "    version: {:?},", version)?;
+    writeln!(write, "    assets: &[")?;
+
+    for entry in entries {
+        writeln!(write, "        ManifestEntry {{")?;
+        writeln!(write, "            uri: {:?},", entry.uri)?;
+        writeln!(
+            write,
+            "            content_encoding: {:?},",
+            entry.content_encoding
+        )?;
+        writeln!(write, "            size: {},", entry.size)?;
+        writeln!(
+            write,
+            "            minified_size: {:?},",
+            entry.minified_size
+        )?;
+        writeln!(
+            write,
+            "            compressed_size: {},",
+            entry.compressed_size
+        )?;
+        writeln!(write, "            hash: {:?},", entry.hash)?;
+        writeln!(write, "        }},")?;
+    }
+
+    writeln!(write, "    ],")?;
+    writeln!(write, "}}")?;
+
+    Ok(())
+}
+
+fn write_asset(
+    mut write: impl Write,
+    asset_metadata: &AssetMetadata,
+    data_file: &Path,
+) -> anyhow::Result<()> {
+    writeln!(write, "    (")?;
+    writeln!(write, "        AssetMetadata {{")?;
+    writeln!(write, "            uri: {:?},", asset_metadata.uri)?;
+    writeln!(
+        write,
+        "            cache_control: {:?},",
+        asset_metadata.cache_control
+    )?;
+    writeln!(
+        write,
+        "            content_encoding: {:?},",
+        asset_metadata.content_encoding
+    )?;
+    writeln!(
+        write,
+        "            content_type: {:?},",
+        asset_metadata.content_type
+    )?;
+    writeln!(write, "            etag: {:?},", asset_metadata.etag)?;
+    writeln!(write, "            headers: &{:?},", asset_metadata.headers)?;
+    writeln!(write, "        }},")?;
+    writeln!(
+        write,
+        "        include_bytes!({:?}) as &[u8],",
+        data_file.display().to_string()
+    )?;
+    writeln!(write, "    ),")?;
+
+    Ok(())
+}
+
+/// Packs the assets into an archive, in the format documented on `ARCHIVE_MAGIC`.
+fn write_archive(mut write: impl Write, entries: &[Entry]) -> anyhow::Result<()> {
+    let count = u16::try_from(entries.len())
+        .map_err(|_| anyhow::anyhow!("Too many assets for an archive: {}", entries.len()))?;
+
+    let mut metadata = Vec::new();
+    let mut data = Vec::new();
+    let mut index = Vec::new();
+
+    for entry in entries {
+        let asset_metadata = entry.metadata();
+
+        let metadata_offset = metadata.len();
+
+        let fields = [
+            Some(asset_metadata.uri),
+            asset_metadata.cache_control,
+            asset_metadata.content_encoding,
+            asset_metadata.content_type,
+            asset_metadata.etag,
+        ];
+
+        for field in fields {
+            write_archive_field(&mut metadata, field, &entry.uri)?;
+        }
+
+        // Bounded by `MAX_ASSET_HEADERS`
+        metadata.extend_from_slice(&(asset_metadata.headers.len() as u16).to_le_bytes());
+
+        for (name, value) in asset_metadata.headers {
+            write_archive_field(&mut metadata, Some(name), &entry.uri)?;
+            write_archive_field(&mut metadata, Some(value), &entry.uri)?;
+        }
+
+        // The serving side loads the metadata of an asset into a fixed buffer, so rather than
+        // breaking the lookup of every asset on the device, an oversized entry is rejected here
+        let metadata_len = metadata.len() - metadata_offset;
+
+        if metadata_len > METADATA_BUF_SIZE {
+            anyhow::bail!(
+                "Metadata of asset {} takes {} bytes, more than the {} bytes the device can load",
+                entry.name(),
+                metadata_len,
+                METADATA_BUF_SIZE
+            );
+        }
+
+        let data_offset = data.len();
+
+        data.extend(fs::read(&entry.data_file)?);
+
+        index.push((
+            metadata_offset,
+            metadata_len,
+            data_offset,
+            data.len() - data_offset,
+        ));
+    }
+
+    let metadata_start = ARCHIVE_HEADER_LEN + entries.len() * ARCHIVE_INDEX_ENTRY_LEN;
+    let data_start = metadata_start + metadata.len();
+
+    let offset =
+        |offset: usize| u32::try_from(offset).map_err(|_| anyhow::anyhow!("Archive exceeds 4GB"));
+
+    write.write_all(&ARCHIVE_MAGIC)?;
+    write.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+    write.write_all(&count.to_le_bytes())?;
+
+    for (metadata_offset, metadata_len, data_offset, data_len) in index {
+        write.write_all(&offset(metadata_start + metadata_offset)?.to_le_bytes())?;
+        write.write_all(&u16::try_from(metadata_len)?.to_le_bytes())?;
+        write.write_all(&offset(data_start + data_offset)?.to_le_bytes())?;
+        write.write_all(&offset(data_len)?.to_le_bytes())?;
+    }
+
+    write.write_all(&metadata)?;
+    write.write_all(&data)?;
+
+    Ok(())
+}
+
+/// Appends a metadata field of the asset at `uri` to the archive metadata.
+fn write_archive_field(
+    metadata: &mut Vec<u8>,
+    field: Option<&str>,
+    uri: &str,
+) -> anyhow::Result<()> {
+    match field {
+        Some(field) => {
+            let len = u16::try_from(field.len())
+                .ok()
+                .filter(|len| *len != ARCHIVE_NONE)
+                .ok_or_else(|| anyhow::anyhow!("Metadata of asset `{}` is too long", uri))?;
+
+            metadata.extend_from_slice(&len.to_le_bytes());
+            metadata.extend_from_slice(field.as_bytes());
+        }
+        None => metadata.extend_from_slice(&ARCHIVE_NONE.to_le_bytes()),
+    }
+
+    Ok(())
+}
+
+pub fn compress(
+    assets_dir: impl AsRef<Path>,
+    output_dir: impl AsRef<Path>,
+    encodings: &[Encoding],
+    track: impl Fn(&Path),
+) -> anyhow::Result<Vec<PathBuf>> {
+    let assets_dir = assets_dir.as_ref();
+    let output_dir = output_dir.as_ref();
+
+    let mut output_files = Vec::new();
+
+    compress_dir(assets_dir, output_dir, encodings, &track, &mut output_files)?;
+
+    Ok(output_files)
+}
+
+fn compress_dir(
+    assets_dir: &Path,
+    output_dir: &Path,
+    encodings: &[Encoding],
+    track: &impl Fn(&Path),
+    output_files: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    track(assets_dir);
+
+    let mut files = fs::read_dir(assets_dir)
+        .and_then(|files| files.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Cannot read assets directory `{}`", assets_dir.display()))?;
+
+    files.sort_by_key(|file| file.file_name());
+
+    for file in files {
+        let path = file.path();
+
+        // File names become part of the asset URIs, so rather than lossily converting them
+        // and possibly ending up with clashing URIs, non-UTF-8 names are rejected
+        let file_name = file
+            .file_name()
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("Asset `{}` has a non-UTF-8 file name", path.display()))?;
+
+        let metadata = fs::metadata(&path)
+            .with_context(|| format!("Cannot read the metadata of asset `{}`", path.display()))?;
+
+        if metadata.is_dir() {
+            compress_dir(
+                &path,
+                &output_dir.join(file_name),
+                encodings,
+                track,
+                output_files,
+            )?;
+        } else if metadata.is_file() {
+            track(&path);
+
+            fs::create_dir_all(output_dir).with_context(|| {
+                format!("Cannot create output directory `{}`", output_dir.display())
+            })?;
+
+            for encoding in encodings {
+                let output_file = output_dir.join(encoding.file_name(&file_name));
+
+                track(&output_file);
+
+                encode(&path, &output_file, *encoding).with_context(|| {
+                    format!(
+                        "Cannot encode asset `{}` into `{}`",
+                        path.display(),
+                        output_file.display()
+                    )
+                })?;
+
+                output_files.push(output_file);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn encode(input_file: &Path, output_file: &Path, encoding: Encoding) -> io::Result<()> {
+    let mut input = fs::File::open(input_file)?;
+    let mut output = fs::File::create(output_file)?;
+
+    match encoding {
+        Encoding::Identity => {
+            io::copy(&mut input, &mut output)?;
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(output, Compression::best());
+
+            io::copy(&mut input, &mut encoder)?;
+
+            encoder.finish()?;
+        }
+        Encoding::Brotli => {
+            let params = BrotliEncoderParams {
+                quality: 11,
+                lgwin: 22,
+                ..Default::default()
+            };
+
+            brotli::BrotliCompress(&mut input, &mut output, &params)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    use super::{
+        compress, stage, write_archive, AssetMetadata, Encoding, Entry, METADATA_BUF_SIZE,
+    };
+
+    const ENCODINGS: &[Encoding] = &[Encoding::Identity, Encoding::Gzip, Encoding::Brotli];
+
+    fn write(path: impl AsRef<Path>, data: &[u8]) {
+        let path = path.as_ref();
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    fn decode(path: &Path) -> Vec<u8> {
+        let mut file = fs::File::open(path).unwrap();
+        let mut data = Vec::new();
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") => flate2::read::GzDecoder::new(file).read_to_end(&mut data),
+            Some("br") => brotli::Decompressor::new(file, 4096).read_to_end(&mut data),
+            _ => file.read_to_end(&mut data),
+        }
+        .unwrap();
+
+        data
+    }
+
+    #[test]
+    fn compress_walks_subdirectories() {
+        let assets = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+
+        write(assets.path().join("index.html"), b"<html></html>");
+        write(assets.path().join("css/style.css"), b"body {}");
+        write(assets.path().join("img/icons/a.svg"), b"<svg></svg>");
+        fs::create_dir(assets.path().join("empty")).unwrap();
+
+        let tracked = RefCell::new(Vec::new());
+
+        let output_files = compress(assets.path(), output.path(), ENCODINGS, |path| {
+            tracked.borrow_mut().push(path.to_path_buf())
+        })
+        .unwrap();
+
+        let expected = [
+            ("css/style.css", &b"body {}"[..]),
+            ("img/icons/a.svg", b"<svg></svg>"),
+            ("index.html", b"<html></html>"),
+        ];
+
+        let expected_files = expected
+            .iter()
+            .flat_map(|(file, _)| {
+                ENCODINGS
+                    .iter()
+                    .map(move |encoding| encoding.file_name(file))
+            })
+            .map(|file| output.path().join(file))
+            .collect::<Vec<_>>();
+
+        assert_eq!(output_files, expected_files);
+
+        for (file, data) in expected {
+            for encoding in ENCODINGS {
+                assert_eq!(decode(&output.path().join(encoding.file_name(file))), data);
+            }
+
+            assert!(tracked.borrow().contains(&assets.path().join(file)));
+        }
+
+        // Directories are tracked too, so that added assets are picked up
+        let tracked = tracked.into_inner();
+
+        for dir in ["", "css", "img", "img/icons", "empty"] {
+            assert!(tracked.contains(&assets.path().join(dir)), "{}", dir);
+        }
+
+        assert!(!output.path().join("empty").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compress_rejects_non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let assets = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+
+        write(
+            assets
+                .path()
+                .join("sub")
+                .join(OsStr::from_bytes(b"\xff.txt")),
+            b"",
+        );
+
+        let error = compress(assets.path(), output.path(), ENCODINGS, |_| ()).unwrap_err();
+
+        assert!(error.to_string().contains("non-UTF-8"), "{:#}", error);
+    }
+
+    fn compress_error(setup: impl FnOnce(&Path, &Path)) -> String {
+        let assets = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+
+        setup(assets.path(), output.path());
+
+        let error = compress(assets.path(), output.path(), ENCODINGS, |_| ()).unwrap_err();
+
+        format!("{:#}", error)
+    }
+
+    #[test]
+    fn compress_reports_missing_assets_dir() {
+        let output = tempfile::tempdir().unwrap();
+        let assets = output.path().join("missing");
+
+        let error = compress(&assets, output.path(), ENCODINGS, |_| ()).unwrap_err();
+
+        assert!(
+            error.to_string().contains(&format!(
+                "Cannot read assets directory `{}`",
+                assets.display()
+            )),
+            "{:#}",
+            error
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compress_reports_unreadable_asset() {
+        let error = compress_error(|assets, _| {
+            // Permissions cannot be relied upon when running as root, but a dangling symlink is never readable
+            std::os::unix::fs::symlink(assets.join("missing"), assets.join("dangling.txt"))
+                .unwrap();
+        });
+
+        assert!(
+            error.contains("Cannot read the metadata of asset") && error.contains("dangling.txt"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn compress_reports_unwritable_output() {
+        let error = compress_error(|assets, output| {
+            write(assets.join("css/style.css"), b"body {}");
+            write(output.join("css"), b"not a directory");
+        });
+
+        assert!(
+            error.contains("Cannot create output directory") && error.contains("css"),
+            "{}",
+            error
+        );
+
+        let error = compress_error(|assets, output| {
+            write(assets.join("index.html"), b"<html></html>");
+            fs::create_dir(output.join("index.html.gz")).unwrap();
+        });
+
+        assert!(
+            error.contains("Cannot encode asset") && error.contains("index.html.gz"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn derive_metadata() {
+        const YEAR: Option<&str> = Some("public, max-age=31536000");
+
+        const HTML: Option<&str> = Some("text/html; charset=utf-8");
+        const JS: Option<&str> = Some("text/javascript; charset=utf-8");
+
+        let mime_types = [
+            ("js", "application/javascript"),
+            ("map", "application/json"),
+        ];
+
+        // The URI, whether to use the additional MIME types, and the expected URI, encoding, type and cache control
+        let cases = [
+            ("/index.html", false, "/", None, HTML, Some("no-cache")),
+            (
+                "/index.html.gz",
+                false,
+                "/",
+                Some("gzip"),
+                HTML,
+                Some("no-cache"),
+            ),
+            ("/app.js", false, "/app.js", None, JS, YEAR),
+            ("/app.js.br", false, "/app.js", Some("br"), JS, YEAR),
+            ("/APP.JS.GZ", false, "/APP.JS", Some("gzip"), JS, YEAR),
+            (
+                "/app.wasm.gz",
+                false,
+                "/app.wasm",
+                Some("gzip"),
+                Some("application/wasm"),
+                YEAR,
+            ),
+            // No extension besides the encoding one
+            ("/LICENSE", false, "/LICENSE", None, None, YEAR),
+            ("/LICENSE.gz", false, "/LICENSE", Some("gzip"), None, YEAR),
+            ("/LICENSE.br", false, "/LICENSE", Some("br"), None, YEAR),
+            // Dots in directory names are not extensions
+            ("/v1.2/app", false, "/v1.2/app", None, None, YEAR),
+            ("/v1.2/app.gz", false, "/v1.2/app", Some("gzip"), None, YEAR),
+            ("/v1.2/app.js", false, "/v1.2/app.js", None, JS, YEAR),
+            ("/unknown.xyz", false, "/unknown.xyz", None, None, YEAR),
+            // Overrides and extensions of the built-in table
+            (
+                "/app.js",
+                true,
+                "/app.js",
+                None,
+                Some("application/javascript"),
+                YEAR,
+            ),
+            (
+                "/app.js.map.gz",
+                true,
+                "/app.js.map",
+                Some("gzip"),
+                Some("application/json"),
+                YEAR,
+            ),
+            ("/index.html", true, "/", None, HTML, Some("no-cache")),
+        ];
+
+        for (uri, overrides, expected_uri, content_encoding, content_type, cache_control) in cases {
+            let asset_metadata = AssetMetadata::derive_with_mime_types(
+                uri,
+                if overrides { &mime_types } else { &[] },
+            );
+
+            assert_eq!(
+                (
+                    asset_metadata.uri,
+                    asset_metadata.content_encoding,
+                    asset_metadata.content_type,
+                    asset_metadata.cache_control,
+                ),
+                (expected_uri, content_encoding, content_type, cache_control),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn rules() {
+        let rules = [
+            ("/index.html", "no-store"),
+            ("/snippets/**", "no-cache"),
+            ("/*.json", "max-age=60"),
+            ("/**/*.js", "public, max-age=31536000, immutable"),
+            ("/**", "max-age=3600"),
+        ]
+        .iter()
+        .map(|(glob, value)| (glob::Pattern::new(glob).unwrap(), *value))
+        .collect::<Vec<_>>();
+
+        let cases = [
+            ("/index.html.gz", Some("no-store")),
+            // The first matching rule wins, even if a later one is more specific
+            ("/snippets/app-1a2b/inline0.js", Some("no-cache")),
+            ("/snippets/app-1a2b/inline0.js.br", Some("no-cache")),
+            ("/app.js.gz", Some("public, max-age=31536000, immutable")),
+            ("/js/app.js", Some("public, max-age=31536000, immutable")),
+            // `*` does not match `/`
+            ("/config.json", Some("max-age=60")),
+            ("/data/config.json", Some("max-age=3600")),
+            // Matched without the encoding suffix
+            ("/config.json.gz", Some("max-age=60")),
+            ("/LICENSE.gz", Some("max-age=3600")),
+        ];
+
+        for (uri, expected) in cases {
+            let asset_metadata = AssetMetadata::derive(uri);
+
+            let path = super::asset_path(uri, &asset_metadata);
+
+            assert_eq!(super::find_rule(&rules, path).copied(), expected, "{}", uri);
+        }
+
+        assert_eq!(super::find_rule(&rules[..3], "/app.js"), None);
+    }
+
+    #[test]
+    fn stage_renames_referenced_assets() {
+        let assets = tempfile::tempdir().unwrap();
+        let staging = tempfile::tempdir().unwrap();
+
+        write(
+            assets.path().join("index.html"),
+            br#"<script src="/app.js"></script><link href='./app.css'><a href="/LICENSE">"LICENSE"</a>"#,
+        );
+        write(assets.path().join("app.js"), b"js");
+        write(assets.path().join("app.css"), b"css");
+        write(assets.path().join("LICENSE"), b"license");
+        write(assets.path().join("unreferenced.txt"), b"txt");
+
+        let staged = stage(assets.path(), staging.path(), true, &[], &|_| ()).unwrap();
+
+        let hashed =
+            |file: &str, data: &[u8]| super::hashed_file_name(file, &super::hash(data)[..8]);
+
+        let app_js = hashed("app.js", b"js");
+        let app_css = hashed("app.css", b"css");
+        let license = hashed("LICENSE", b"license");
+
+        let mut files = staged.keys().cloned().collect::<Vec<_>>();
+        files.sort();
+
+        let mut expected = vec![
+            app_css.clone(),
+            app_js.clone(),
+            license.clone(),
+            "index.html".to_string(),
+            "unreferenced.txt".to_string(),
+        ];
+        expected.sort();
+
+        assert_eq!(files, expected);
+
+        for file in [&app_js, &app_css, &license] {
+            assert!(staged[file].hashed, "{}", file);
+        }
+
+        for file in ["index.html", "unreferenced.txt"] {
+            assert!(!staged[file].hashed, "{}", file);
+        }
+
+        assert_eq!(
+            fs::read_to_string(staging.path().join("index.html")).unwrap(),
+            format!(
+                r#"<script src="/{}"></script><link href='./{}'><a href="/{}">"LICENSE"</a>"#,
+                app_js, app_css, license
+            )
+        );
+    }
+
+    fn entry<'a>(
+        dir: &Path,
+        uri: &str,
+        data: &[u8],
+        headers: &'a [(&'a str, &'a str)],
+    ) -> Entry<'a> {
+        let data_file = dir.join(super::hash(uri.as_bytes()));
+
+        fs::write(&data_file, data).unwrap();
+
+        Entry {
+            uri: uri.into(),
+            cache_control: Some("no-cache".into()),
+            content_encoding: None,
+            content_type: Some("text/plain; charset=utf-8".into()),
+            etag: super::etag(data),
+            headers,
+            hash: super::hash(data),
+            size: data.len() as _,
+            minified_size: None,
+            compressed_size: data.len() as _,
+            data_file,
+        }
+    }
+
+    #[test]
+    fn check_budgets() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let entries = [
+            entry(dir.path(), "/index.html", &[0; 100], &[]),
+            entry(dir.path(), "/app.js", &[1; 300], &[]),
+            entry(dir.path(), "/app.wasm", &[2; 600], &[]),
+        ];
+
+        type Case<'a> = (Option<u64>, Option<u64>, Option<u8>, &'a [&'a str]);
+
+        // The total budget, the per-asset budget, the warning threshold, and the expected violations
+        let cases: [Case; 8] = [
+            (None, None, None, &[]),
+            // A budget is only exceeded when strictly over it
+            (Some(1000), Some(600), None, &[]),
+            (
+                Some(999),
+                None,
+                None,
+                &["Total of 1000 bytes exceeds the budget of 999 bytes"],
+            ),
+            // Above the warning threshold, but within the budget
+            (Some(1100), None, Some(50), &[]),
+            (None, Some(600), Some(50), &[]),
+            (
+                None,
+                Some(299),
+                None,
+                &[
+                    "`/app.js` (identity) takes 300 bytes, exceeding the per-asset budget of 299 bytes",
+                    "`/app.wasm` (identity) takes 600 bytes, exceeding the per-asset budget of 299 bytes",
+                ],
+            ),
+            (
+                Some(999),
+                Some(500),
+                Some(10),
+                &[
+                    "Total of 1000 bytes exceeds the budget of 999 bytes",
+                    "`/app.wasm` (identity) takes 600 bytes, exceeding the per-asset budget of 500 bytes",
+                ],
+            ),
+            (Some(2000), Some(1000), Some(0), &[]),
+        ];
+
+        for (total_budget, asset_budget, budget_warning, violations) in cases {
+            let mut builder = super::Builder::new("test", dir.path());
+
+            builder.total_budget = total_budget;
+            builder.asset_budget = asset_budget;
+            builder.budget_warning = budget_warning;
+
+            let result = builder.check_budgets(&entries);
+
+            let case = (total_budget, asset_budget, budget_warning);
+
+            if violations.is_empty() {
+                assert!(result.is_ok(), "{:?}: {:#}", case, result.unwrap_err());
+                continue;
+            }
+
+            let report = result.expect_err(&format!("{:?}", case)).to_string();
+
+            assert!(
+                report.starts_with("Assets of module `test` exceed their size budget:\n"),
+                "{:?}: {}",
+                case,
+                report
+            );
+
+            for violation in violations {
+                assert!(
+                    report.contains(&format!("- {}", violation)),
+                    "{:?}: {}",
+                    case,
+                    report
+                );
+            }
+
+            // The report lists every asset, largest first, and the total
+            let listed = report
+                .lines()
+                .skip_while(|line| !line.starts_with("Assets by embedded size"))
+                .skip(1)
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                listed,
+                [
+                    "       600 (       600)  `/app.wasm` (identity)",
+                    "       300 (       300)  `/app.js` (identity)",
+                    "       100 (       100)  `/index.html` (identity)",
+                    "      1000               total",
+                ],
+                "{:?}",
+                case
+            );
+        }
+    }
+
+    #[cfg(feature = "assets-serve-core")]
+    #[test]
+    fn archive_round_trip() {
+        use core::convert::Infallible;
+
+        use embedded_io::blocking::{Read, Seek};
+        use embedded_io::{Io, SeekFrom};
+
+        use crate::assets::serve::{Archive, AssetSource};
+        use crate::assets::{MAX_ASSET_HEADERS, SECURITY_HEADERS};
+
+        struct Memory(Vec<u8>, usize);
+
+        impl Io for Memory {
+            type Error = Infallible;
+        }
+
+        impl Read for Memory {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+                let len = buf.len().min(self.0.len() - self.1);
+
+                buf[..len].copy_from_slice(&self.0[self.1..self.1 + len]);
+                self.1 += len;
+
+                Ok(len)
+            }
+        }
+
+        impl Seek for Memory {
+            fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+                self.1 = match pos {
+                    SeekFrom::Start(offset) => offset as usize,
+                    SeekFrom::End(offset) => (self.0.len() as i64 + offset) as usize,
+                    SeekFrom::Current(offset) => (self.1 as i64 + offset) as usize,
+                };
+
+                Ok(self.1 as _)
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let entries = [
+            entry(dir.path(), "/", b"<html></html>", SECURITY_HEADERS),
+            entry(
+                dir.path(),
+                "/hello.txt",
+                b"Hello, world!",
+                &[("X-Test", "1")],
+            ),
+            entry(dir.path(), "/empty.txt", b"", &[]),
+        ];
+
+        let mut archive = Vec::new();
+        write_archive(&mut archive, &entries).unwrap();
+
+        let mut archive = Archive::new(Memory(archive, 0)).unwrap();
+
+        assert_eq!(archive.count().unwrap(), entries.len());
+
+        for (index, entry) in entries.iter().enumerate() {
+            let mut buf = [0_u8; METADATA_BUF_SIZE];
+            let mut headers = [("", ""); MAX_ASSET_HEADERS];
+
+            let (metadata, len) = archive.metadata(index, &mut buf, &mut headers).unwrap();
+
+            let expected = entry.metadata();
+
+            assert_eq!(metadata.uri, expected.uri);
+            assert_eq!(metadata.cache_control, expected.cache_control);
+            assert_eq!(metadata.content_encoding, expected.content_encoding);
+            assert_eq!(metadata.content_type, expected.content_type);
+            assert_eq!(metadata.etag, expected.etag);
+            assert_eq!(metadata.headers, expected.headers);
+
+            let data = fs::read(&entry.data_file).unwrap();
+            assert_eq!(len, data.len());
+
+            // Read in small chunks and from an offset, as the handlers do
+            let mut read = Vec::new();
+            let mut chunk = [0_u8; 4];
+
+            loop {
+                let len = archive.read_data(index, read.len(), &mut chunk).unwrap();
+
+                if len == 0 {
+                    break;
+                }
+
+                read.extend_from_slice(&chunk[..len]);
+            }
+
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn archive_rejects_oversized_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let value = "x".repeat(METADATA_BUF_SIZE);
+        let headers = [("X-Large", value.as_str())];
+
+        let entries = [entry(dir.path(), "/large.txt", b"large", &headers)];
+
+        let error = write_archive(&mut Vec::new(), &entries).unwrap_err();
+
+        assert!(error.to_string().contains("`/large.txt`"), "{}", error);
+    }
+}
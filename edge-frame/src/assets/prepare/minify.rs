@@ -0,0 +1,492 @@
+//! Conservative comment and whitespace stripping for text assets, as per `Builder::minify`.
+//!
+//! These are not full-blown minifiers: tokens are never renamed, rewritten or reordered, and
+//! anything which cannot be classified with certainty (strings, regular expressions, template literals,
+//! the contents of `pre` elements...) is kept verbatim. Comments starting with `/*!` (e.g. licenses) are kept too.
+
+/// Characters next to which whitespace is never significant in CSS.
+const CSS_SEPARATORS: &[char] = &['{', '}', ';', ',', '>'];
+
+/// Characters next to which whitespace is never significant in JavaScript.
+const JS_SEPARATORS: &[char] = &['{', '}', '(', ')', '[', ']', ';', ',', '=', ':'];
+
+/// Characters after which a `/` starts a regular expression rather than a division in JavaScript.
+const JS_REGEX_PREFIXES: &[char] = &[
+    '(', ',', '=', ':', '[', '!', '&', '|', '?', '{', '}', ';', '+', '-', '*', '%', '<', '>', '~',
+    '^',
+];
+
+/// Keywords after which a `/` starts a regular expression rather than a division in JavaScript.
+const JS_REGEX_KEYWORDS: &[&str] = &[
+    "return",
+    "typeof",
+    "instanceof",
+    "in",
+    "of",
+    "new",
+    "delete",
+    "void",
+    "throw",
+    "case",
+    "do",
+    "else",
+    "yield",
+    "await",
+];
+
+pub fn css(css: &str) -> String {
+    let chars = css.chars().collect::<Vec<_>>();
+
+    let mut out = String::with_capacity(css.len());
+    let mut space = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            space = true;
+            i += 1;
+
+            continue;
+        }
+
+        let end = if c == '/' && chars.get(i + 1) == Some(&'*') {
+            let end = find(&chars, i + 2, "*/").map_or(chars.len(), |end| end + 2);
+
+            if chars.get(i + 2) != Some(&'!') {
+                space = true;
+                i = end;
+
+                continue;
+            }
+
+            end
+        } else if c == '"' || c == '\'' {
+            string_end(&chars, i)
+        } else {
+            i + 1
+        };
+
+        if space {
+            if let Some(last) = out.chars().last() {
+                if !CSS_SEPARATORS.contains(&last) && last != ':' && !CSS_SEPARATORS.contains(&c) {
+                    out.push(' ');
+                }
+            }
+        }
+
+        space = false;
+
+        if c == '}' && out.ends_with(';') {
+            out.pop();
+        }
+
+        out.extend(&chars[i..end]);
+        i = end;
+    }
+
+    out
+}
+
+pub fn js(js: &str) -> String {
+    let chars = js.chars().collect::<Vec<_>>();
+
+    let mut out = String::with_capacity(js.len());
+    let mut space = false;
+    let mut newline = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == '\n' || c == '\r' {
+            newline = true;
+            i += 1;
+
+            continue;
+        } else if c.is_whitespace() {
+            space = true;
+            i += 1;
+
+            continue;
+        }
+
+        let end = if c == '/' && chars.get(i + 1) == Some(&'/') {
+            // The newline ending the comment is kept, as it might terminate a statement
+            i = chars[i..]
+                .iter()
+                .position(|c| *c == '\n')
+                .map_or(chars.len(), |position| i + position);
+
+            continue;
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            let end = find(&chars, i + 2, "*/").map_or(chars.len(), |end| end + 2);
+
+            if chars.get(i + 2) != Some(&'!') {
+                if chars[i..end].contains(&'\n') {
+                    newline = true;
+                } else {
+                    space = true;
+                }
+
+                i = end;
+
+                continue;
+            }
+
+            end
+        } else if c == '"' || c == '\'' {
+            string_end(&chars, i)
+        } else if c == '`' {
+            template_end(&chars, i)
+        } else if c == '/' && is_regex_allowed(&out) {
+            regex_end(&chars, i).unwrap_or(i + 1)
+        } else {
+            i + 1
+        };
+
+        if let Some(last) = out.chars().last() {
+            if newline && !matches!(last, '{' | ';' | ',') && c != '}' {
+                out.push('\n');
+            } else if (newline || space)
+                && !JS_SEPARATORS.contains(&last)
+                && !JS_SEPARATORS.contains(&c)
+            {
+                out.push(' ');
+            }
+        }
+
+        space = false;
+        newline = false;
+
+        out.extend(&chars[i..end]);
+        i = end;
+    }
+
+    out
+}
+
+/// Strips comments and collapses whitespace in the text of an HTML document,
+/// and optionally minifies its inline styles and scripts.
+pub fn html(html: &str, css: bool, js: bool) -> String {
+    let chars = html.chars().collect::<Vec<_>>();
+    let lower = html.to_ascii_lowercase().chars().collect::<Vec<_>>();
+
+    let mut out = String::with_capacity(html.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            let end = chars[i..]
+                .iter()
+                .position(|c| !c.is_whitespace())
+                .map_or(chars.len(), |position| i + position);
+
+            let newline = chars[i..end].contains(&'\n');
+
+            // Whitespace runs separated by a stripped comment collapse as well
+            if out.ends_with(' ') && newline {
+                out.pop();
+            }
+
+            if !out.ends_with(char::is_whitespace) {
+                out.push(if newline { '\n' } else { ' ' });
+            }
+
+            i = end;
+        } else if starts_with(&chars, i, "<!--") && !starts_with(&chars, i, "<!--[") {
+            i = find(&chars, i + 4, "-->").map_or(chars.len(), |end| end + 3);
+        } else if c == '<'
+            && chars
+                .get(i + 1)
+                .is_some_and(|c| c.is_ascii_alphabetic() || matches!(c, '/' | '!' | '?'))
+        {
+            let end = tag_end(&chars, i);
+
+            let tag = lower[i..end].iter().collect::<String>();
+
+            let name = tag[1..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect::<String>();
+
+            out.extend(&chars[i..end]);
+            i = end;
+
+            if matches!(name.as_str(), "script" | "style" | "pre" | "textarea") {
+                let content_end = find(&lower, i, &format!("</{}", name)).unwrap_or(chars.len());
+
+                let content = chars[i..content_end].iter().collect::<String>();
+
+                if name == "style" && css {
+                    out.push_str(&self::css(&content));
+                } else if name == "script" && js && is_js_script(&tag) {
+                    out.push_str(&self::js(&content));
+                } else {
+                    out.push_str(&content);
+                }
+
+                i = content_end;
+            }
+        } else {
+            out.push(c);
+            i += 1;
+        }
+    }
+
+    out.trim().to_string()
+}
+
+/// Checks whether a `script` tag, in lowercase, holds JavaScript rather than e.g. JSON or a template.
+fn is_js_script(tag: &str) -> bool {
+    let Some(position) = tag.find("type=") else {
+        return true;
+    };
+
+    let value = tag[position + 5..]
+        .trim_start_matches(['"', '\''])
+        .split(['"', '\'', ' ', '>'])
+        .next()
+        .unwrap_or("");
+
+    matches!(
+        value,
+        "" | "module" | "text/javascript" | "application/javascript"
+    )
+}
+
+/// Returns the index after the end of the tag starting at `start`, skipping quoted attribute values.
+fn tag_end(chars: &[char], start: usize) -> usize {
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '>' => return i + 1,
+            '"' | '\'' if chars[i - 1] == '=' => i = string_end(chars, i),
+            _ => i += 1,
+        }
+    }
+
+    chars.len()
+}
+
+/// Returns the index after the end of the string literal starting at `start`.
+fn string_end(chars: &[char], start: usize) -> usize {
+    let quote = chars[start];
+
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '\n' => return i,
+            c if c == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+
+    chars.len()
+}
+
+/// Returns the index after the end of the JavaScript template literal starting at `start`.
+fn template_end(chars: &[char], start: usize) -> usize {
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '`' => return i + 1,
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                let mut depth = 0;
+
+                i += 1;
+
+                while i < chars.len() {
+                    match chars[i] {
+                        '{' => {
+                            depth += 1;
+                            i += 1;
+                        }
+                        '}' => {
+                            depth -= 1;
+                            i += 1;
+
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        '"' | '\'' => i = string_end(chars, i),
+                        '`' => i = template_end(chars, i),
+                        _ => i += 1,
+                    }
+                }
+            }
+            _ => i += 1,
+        }
+    }
+
+    chars.len()
+}
+
+/// Returns the index after the end of the JavaScript regular expression starting at `start`, if it is one,
+/// i.e. if it is terminated on the same line.
+fn regex_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut class = false;
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '\n' => return None,
+            '[' => {
+                class = true;
+                i += 1;
+            }
+            ']' => {
+                class = false;
+                i += 1;
+            }
+            '/' if !class => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+/// Checks whether a `/` following the already minified JavaScript in `out` starts a regular expression.
+fn is_regex_allowed(out: &str) -> bool {
+    let Some(last) = out.chars().last() else {
+        return true;
+    };
+
+    if last == '+' || last == '-' {
+        // Unless it follows an increment or decrement, e.g. `i++ / 2`
+        !out.ends_with("++") && !out.ends_with("--")
+    } else if JS_REGEX_PREFIXES.contains(&last) {
+        true
+    } else {
+        let word = out
+            .rsplit(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+            .next()
+            .unwrap_or("");
+
+        JS_REGEX_KEYWORDS.contains(&word)
+    }
+}
+
+fn starts_with(chars: &[char], start: usize, pattern: &str) -> bool {
+    pattern
+        .chars()
+        .enumerate()
+        .all(|(offset, c)| chars.get(start + offset) == Some(&c))
+}
+
+fn find(chars: &[char], start: usize, pattern: &str) -> Option<usize> {
+    (start..chars.len()).find(|i| starts_with(chars, *i, pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn css() {
+        let cases = [
+            ("a {\n  color: red;\n}\n", "a{color:red}"),
+            ("a , b > c { margin : 0 auto }", "a,b>c{margin :0 auto}"),
+            ("a :hover{}", "a :hover{}"),
+            ("/* comment */ a{}", "a{}"),
+            ("/*! license */\na{}", "/*! license */ a{}"),
+            (
+                "a{content: \"  /* kept */  \"}",
+                "a{content:\"  /* kept */  \"}",
+            ),
+        ];
+
+        for (css, minified) in cases {
+            assert_eq!(super::css(css), minified, "{:?}", css);
+        }
+    }
+
+    #[test]
+    fn js() {
+        let cases = [
+            // Newlines might terminate statements
+            ("return\nx", "return\nx"),
+            ("a = 1\nb = 2", "a=1\nb=2"),
+            ("{\n  a();\n}\n", "{a();}"),
+            // Divisions and regular expressions
+            ("a / b / c", "a / b / c"),
+            ("x = /re/g", "x=/re/g"),
+            ("x = a / 2, y = /[/]/.test(s)", "x=a / 2,y=/[/]/.test(s)"),
+            ("i++ / 2", "i++ / 2"),
+            ("return /re/.test(s)", "return /re/.test(s)"),
+            // Literals are kept verbatim
+            ("`${ {a:1}.a }`", "`${ {a:1}.a }`"),
+            ("`a ${ `b ${ c }` } // d`", "`a ${ `b ${ c }` } // d`"),
+            ("s = \"a  // b\"", "s=\"a  // b\""),
+            ("s = 'a  /* b */'", "s='a  /* b */'"),
+            // Comments
+            ("a(); // comment\nb();", "a();b();"),
+            ("a /* comment */ b", "a b"),
+            ("a /* multi\nline */ b", "a\nb"),
+            ("/*! license */\nvar a = 1;", "/*! license */\nvar a=1;"),
+        ];
+
+        for (js, minified) in cases {
+            assert_eq!(super::js(js), minified, "{:?}", js);
+        }
+    }
+
+    #[test]
+    fn html() {
+        let cases = [
+            ("<p>  a  <!-- c -->  b </p>", "<p> a b </p>"),
+            ("<p>\n  a\n</p>\n", "<p>\na\n</p>"),
+            (
+                "<!--[if IE]><p>IE</p><![endif]-->",
+                "<!--[if IE]><p>IE</p><![endif]-->",
+            ),
+            (
+                "<p title=\"a  >  b\">  x</p>",
+                "<p title=\"a  >  b\"> x</p>",
+            ),
+            // Whitespace is significant in these
+            ("<pre>\n  a\n   b\n</pre>", "<pre>\n  a\n   b\n</pre>"),
+            ("<textarea>  a  </textarea>", "<textarea>  a  </textarea>"),
+            ("<PRE>  a  </PRE>", "<PRE>  a  </PRE>"),
+            // Inline styles and scripts
+            (
+                "<style>\n  a { color: red; }\n</style>",
+                "<style>a{color:red}</style>",
+            ),
+            (
+                "<script>\n  var a = 1;\n</script>",
+                "<script>var a=1;</script>",
+            ),
+            (
+                "<script type=\"module\">var a = 1;</script>",
+                "<script type=\"module\">var a=1;</script>",
+            ),
+            (
+                "<script type=\"application/json\">{ \"a\" : 1 }</script>",
+                "<script type=\"application/json\">{ \"a\" : 1 }</script>",
+            ),
+            (
+                "<script>if (a < b) x()</script>",
+                "<script>if(a < b)x()</script>",
+            ),
+        ];
+
+        for (html, minified) in cases {
+            assert_eq!(super::html(html, true, true), minified, "{:?}", html);
+        }
+
+        // Inline styles and scripts are only minified when asked to
+        let html = "<style> a { } </style><script> a = 1 </script>";
+
+        assert_eq!(super::html(html, false, false), html);
+    }
+}
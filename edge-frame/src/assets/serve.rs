@@ -0,0 +1,1463 @@
+use core::convert::Infallible;
+use core::fmt::{Debug, Write as _};
+
+use embedded_io::blocking::{Read, ReadExactError, Seek};
+use embedded_io::SeekFrom;
+
+use log::warn;
+
+pub use super::{AssetMetadata, Manifest, ManifestEntry, METADATA_BUF_SIZE};
+
+#[cfg(feature = "assets-serve")]
+pub use svc::*;
+
+use super::{
+    ARCHIVE_HEADER_LEN, ARCHIVE_INDEX_ENTRY_LEN, ARCHIVE_MAGIC, ARCHIVE_NONE, ARCHIVE_VERSION,
+    MAX_ASSET_HEADERS,
+};
+
+pub type Asset = (&'static str, &'static [u8]);
+
+pub type Assets = &'static [(AssetMetadata<'static>, &'static [u8])];
+
+/// The methods to which the asset handlers respond, as reported in the `Allow` header of a 405 response.
+const ALLOWED_METHODS: &str = "GET, HEAD";
+
+/// The headers of a response without a body, e.g. a 404 one. Declaring the empty body keeps
+/// HTTP/1.1 clients from waiting for the connection to close.
+const EMPTY_BODY_HEADERS: &[(&str, &str)] = &[("Content-Length", "0")];
+
+/// The capacity of the response headers: the ones set for every asset, plus its additional headers.
+const RESPONSE_HEADERS: usize = 8 + MAX_ASSET_HEADERS;
+
+/// The size of the chunks in which the data of an asset is copied from an `AssetSource` to the response.
+pub const DATA_CHUNK_SIZE: usize = 512;
+
+/// The method of a request, as far as serving assets is concerned.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RequestMethod {
+    Get,
+    Head,
+    /// Any other method, which gets a 405 response
+    Other,
+}
+
+/// The parts of an HTTP request which the response for an asset depends on.
+///
+/// Implemented for the requests of the `embedded-svc` servers with the `assets-serve` feature,
+/// and for `asynch::RawConnection`.
+pub trait AssetRequest {
+    fn method(&self) -> RequestMethod;
+
+    /// The request URI, including any query.
+    fn uri(&self) -> &str;
+
+    /// The value of the request header `name`, matched case-insensitively.
+    fn header(&self, name: &str) -> Option<&str>;
+}
+
+/// The headers of an asset response, which borrow their values from the asset metadata and the caller.
+struct ResponseHeaders<'a, const N: usize>(heapless::Vec<(&'a str, &'a str), N>);
+
+impl<'a, const N: usize> ResponseHeaders<'a, N> {
+    const fn new() -> Self {
+        Self(heapless::Vec::new())
+    }
+
+    /// Sets the header `name`, replacing any previous value.
+    ///
+    /// Only called for the headers which `RESPONSE_HEADERS` accounts for, i.e. the fixed ones
+    /// and at most `MAX_ASSET_HEADERS` additional ones.
+    fn set(&mut self, name: &'a str, value: &'a str) {
+        if let Some(header) = self
+            .0
+            .iter_mut()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        {
+            header.1 = value;
+        } else {
+            self.0
+                .push((name, value))
+                .expect("RESPONSE_HEADERS accounts for all headers");
+        }
+    }
+
+    fn as_slice(&self) -> &[(&'a str, &'a str)] {
+        &self.0
+    }
+}
+
+#[macro_export]
+macro_rules! assets {
+    ($module:literal) => {{
+        use $crate::assets::AssetMetadata;
+
+        include!(concat!(
+            env!("OUT_DIR"),
+            "/edge_frame_assets/",
+            $module,
+            "/assets.rs"
+        ))
+    }};
+}
+
+#[macro_export]
+macro_rules! assets_manifest {
+    ($module:literal) => {{
+        use $crate::assets::{Manifest, ManifestEntry};
+
+        include!(concat!(
+            env!("OUT_DIR"),
+            "/edge_frame_assets/",
+            $module,
+            "/manifest.rs"
+        ))
+    }};
+}
+
+/// A store of assets the UI is served from, e.g. the `Assets` table embedded in the firmware
+/// or an `Archive` living in a flash partition of its own.
+pub trait AssetSource {
+    type Error: Debug;
+
+    /// Returns the number of assets in the source.
+    fn count(&mut self) -> Result<usize, Self::Error>;
+
+    /// Loads the metadata of the asset at `index`, using `buf` as storage for its strings
+    /// and `headers` as storage for its additional headers, and returns it together with the length of the asset data.
+    fn metadata<'b>(
+        &mut self,
+        index: usize,
+        buf: &'b mut [u8],
+        headers: &'b mut [(&'b str, &'b str)],
+    ) -> Result<(AssetMetadata<'b>, usize), Self::Error>;
+
+    /// Reads the data of the asset at `index`, starting at `offset`, into `buf`.
+    fn read_data(
+        &mut self,
+        index: usize,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error>;
+}
+
+impl AssetSource for Assets {
+    type Error = Infallible;
+
+    fn count(&mut self) -> Result<usize, Self::Error> {
+        Ok(self.len())
+    }
+
+    fn metadata<'b>(
+        &mut self,
+        index: usize,
+        _buf: &'b mut [u8],
+        _headers: &'b mut [(&'b str, &'b str)],
+    ) -> Result<(AssetMetadata<'b>, usize), Self::Error> {
+        let (asset_metadata, data) = &self[index];
+
+        Ok((asset_metadata.clone(), data.len()))
+    }
+
+    fn read_data(
+        &mut self,
+        index: usize,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        let data = &self[index].1[offset..];
+        let len = data.len().min(buf.len());
+
+        buf[..len].copy_from_slice(&data[..len]);
+
+        Ok(len)
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError<E> {
+    Io(E),
+    InvalidFormat,
+    BufferTooSmall,
+}
+
+impl<E> From<ReadExactError<E>> for ArchiveError<E> {
+    fn from(e: ReadExactError<E>) -> Self {
+        match e {
+            ReadExactError::UnexpectedEof => Self::InvalidFormat,
+            ReadExactError::Other(e) => Self::Io(e),
+        }
+    }
+}
+
+/// An `AssetSource` reading the archive produced by `prepare` from any seekable storage,
+/// e.g. a flash partition.
+pub struct Archive<R> {
+    read: R,
+    count: usize,
+}
+
+impl<R> Archive<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(mut read: R) -> Result<Self, ArchiveError<R::Error>> {
+        let mut header = [0_u8; ARCHIVE_HEADER_LEN];
+
+        read.seek(SeekFrom::Start(0)).map_err(ArchiveError::Io)?;
+        read.read_exact(&mut header)?;
+
+        if header[..4] != ARCHIVE_MAGIC
+            || u16::from_le_bytes([header[4], header[5]]) != ARCHIVE_VERSION
+        {
+            return Err(ArchiveError::InvalidFormat);
+        }
+
+        let count = u16::from_le_bytes([header[6], header[7]]) as usize;
+
+        Ok(Self { read, count })
+    }
+
+    pub fn release(self) -> R {
+        self.read
+    }
+
+    /// Returns the metadata offset and length, and the data offset and length of the asset at `index`.
+    fn index_entry(
+        &mut self,
+        index: usize,
+    ) -> Result<(u64, usize, u64, usize), ArchiveError<R::Error>> {
+        if index >= self.count {
+            return Err(ArchiveError::InvalidFormat);
+        }
+
+        let mut entry = [0_u8; ARCHIVE_INDEX_ENTRY_LEN];
+
+        self.read
+            .seek(SeekFrom::Start(
+                (ARCHIVE_HEADER_LEN + index * ARCHIVE_INDEX_ENTRY_LEN) as _,
+            ))
+            .map_err(ArchiveError::Io)?;
+        self.read.read_exact(&mut entry)?;
+
+        Ok((
+            u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as _,
+            u16::from_le_bytes([entry[4], entry[5]]) as _,
+            u32::from_le_bytes([entry[6], entry[7], entry[8], entry[9]]) as _,
+            u32::from_le_bytes([entry[10], entry[11], entry[12], entry[13]]) as _,
+        ))
+    }
+}
+
+impl<R> AssetSource for Archive<R>
+where
+    R: Read + Seek,
+{
+    type Error = ArchiveError<R::Error>;
+
+    fn count(&mut self) -> Result<usize, Self::Error> {
+        Ok(self.count)
+    }
+
+    fn metadata<'b>(
+        &mut self,
+        index: usize,
+        buf: &'b mut [u8],
+        headers: &'b mut [(&'b str, &'b str)],
+    ) -> Result<(AssetMetadata<'b>, usize), Self::Error> {
+        let (metadata_offset, metadata_len, _, data_len) = self.index_entry(index)?;
+
+        if metadata_len > buf.len() {
+            return Err(ArchiveError::BufferTooSmall);
+        }
+
+        self.read
+            .seek(SeekFrom::Start(metadata_offset))
+            .map_err(ArchiveError::Io)?;
+        self.read.read_exact(&mut buf[..metadata_len])?;
+
+        let mut metadata: &'b [u8] = &buf[..metadata_len];
+
+        let uri = archive_field(&mut metadata)?.ok_or(ArchiveError::InvalidFormat)?;
+        let cache_control = archive_field(&mut metadata)?;
+        let content_encoding = archive_field(&mut metadata)?;
+        let content_type = archive_field(&mut metadata)?;
+        let etag = archive_field(&mut metadata)?;
+
+        let headers_count = archive_u16(&mut metadata)? as usize;
+
+        if headers_count > headers.len() {
+            return Err(ArchiveError::BufferTooSmall);
+        }
+
+        for header in headers[..headers_count].iter_mut() {
+            *header = (
+                archive_field(&mut metadata)?.ok_or(ArchiveError::InvalidFormat)?,
+                archive_field(&mut metadata)?.ok_or(ArchiveError::InvalidFormat)?,
+            );
+        }
+
+        let asset_metadata = AssetMetadata {
+            uri,
+            cache_control,
+            content_encoding,
+            content_type,
+            etag,
+            headers: &headers[..headers_count],
+        };
+
+        Ok((asset_metadata, data_len))
+    }
+
+    fn read_data(
+        &mut self,
+        index: usize,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        let (_, _, data_offset, data_len) = self.index_entry(index)?;
+
+        let len = data_len.saturating_sub(offset).min(buf.len());
+
+        self.read
+            .seek(SeekFrom::Start(data_offset + offset as u64))
+            .map_err(ArchiveError::Io)?;
+        self.read.read_exact(&mut buf[..len])?;
+
+        Ok(len)
+    }
+}
+
+/// Takes a `u16` off the metadata of an archived asset.
+fn archive_u16<E>(metadata: &mut &[u8]) -> Result<u16, ArchiveError<E>> {
+    if metadata.len() < 2 {
+        return Err(ArchiveError::InvalidFormat);
+    }
+
+    let value = u16::from_le_bytes([metadata[0], metadata[1]]);
+    *metadata = &metadata[2..];
+
+    Ok(value)
+}
+
+/// Takes a string field off the metadata of an archived asset.
+fn archive_field<'b, E>(metadata: &mut &'b [u8]) -> Result<Option<&'b str>, ArchiveError<E>> {
+    let len = archive_u16(metadata)?;
+
+    if len == ARCHIVE_NONE {
+        Ok(None)
+    } else if len as usize > metadata.len() {
+        Err(ArchiveError::InvalidFormat)
+    } else {
+        let (value, rest) = metadata.split_at(len as usize);
+        *metadata = rest;
+
+        core::str::from_utf8(value)
+            .map(Some)
+            .map_err(|_| ArchiveError::InvalidFormat)
+    }
+}
+
+/// Looks up the asset matching the path of a request URI.
+///
+/// Paths whose last segment has no extension (e.g. `/wifi`) are assumed to be
+/// client-side routes and resolve to `/index.html`, so that deep links keep working on reload.
+///
+/// When the asset is available in several encodings, the first one accepted by the client
+/// as per `accept_encoding` is returned. If none is accepted, the first one is returned anyway.
+pub fn lookup(
+    assets: Assets,
+    uri: &str,
+    accept_encoding: Option<&str>,
+) -> Option<(AssetMetadata<'static>, &'static [u8])> {
+    let find = |path: &str| {
+        let mut variants = assets.iter().filter(|(metadata, _)| metadata.uri == path);
+
+        variants
+            .clone()
+            .find(|(metadata, _)| is_accepted(accept_encoding, metadata.content_encoding))
+            .or_else(|| variants.next())
+            .cloned()
+    };
+
+    let path = request_path(uri);
+
+    find(path).or_else(|| {
+        if is_client_route(path) {
+            find("/")
+        } else {
+            None
+        }
+    })
+}
+
+/// Same as `lookup`, but for any `AssetSource`. Returns the index of the matching asset.
+pub fn lookup_source<S: AssetSource>(
+    source: &mut S,
+    uri: &str,
+    accept_encoding: Option<&str>,
+) -> Result<Option<usize>, S::Error> {
+    let path = request_path(uri);
+
+    let index = find_source(source, path, accept_encoding)?;
+
+    if index.is_none() && is_client_route(path) {
+        find_source(source, "/", accept_encoding)
+    } else {
+        Ok(index)
+    }
+}
+
+fn find_source<S: AssetSource>(
+    source: &mut S,
+    path: &str,
+    accept_encoding: Option<&str>,
+) -> Result<Option<usize>, S::Error> {
+    let mut buf = [0_u8; METADATA_BUF_SIZE];
+
+    let mut first = None;
+
+    for index in 0..source.count()? {
+        let mut asset_headers = [("", ""); MAX_ASSET_HEADERS];
+
+        let (asset_metadata, _) = source.metadata(index, &mut buf, &mut asset_headers)?;
+
+        if asset_metadata.uri == path {
+            if is_accepted(accept_encoding, asset_metadata.content_encoding) {
+                return Ok(Some(index));
+            }
+
+            first.get_or_insert(index);
+        }
+    }
+
+    Ok(first)
+}
+
+/// Strips the query and fragment from a request URI and maps `/index.html` to `/`.
+fn request_path(uri: &str) -> &str {
+    let path = uri.split(['?', '#']).next().unwrap_or("");
+
+    if path.eq_ignore_ascii_case("/index.html") {
+        "/"
+    } else {
+        path
+    }
+}
+
+fn is_client_route(path: &str) -> bool {
+    !path.rsplit('/').next().unwrap_or("").contains('.')
+}
+
+/// Sets the response headers for an asset of length `len` as per the method, conditional and range headers
+/// of the request, and returns the response status together with the range of the asset data
+/// to send as the response body, if any.
+///
+/// The `Content-Length` of the body is always declared (except for a 304 response, which has none), so that the server neither has to chunk the response
+/// nor close the connection after it. A `HEAD` request gets the same headers as a `GET` one but no body,
+/// while any other method is rejected with a 405.
+fn prepare_response<'a, const N: usize>(
+    headers: &mut ResponseHeaders<'a, N>,
+    content_range: &'a mut heapless::String<48>,
+    content_len: &'a mut heapless::String<20>,
+    asset_metadata: &AssetMetadata<'a>,
+    len: usize,
+    request: &impl AssetRequest,
+) -> (u16, Option<(usize, usize)>) {
+    let head = match request.method() {
+        RequestMethod::Get => false,
+        RequestMethod::Head => true,
+        RequestMethod::Other => {
+            headers.set("Allow", ALLOWED_METHODS);
+            headers.set("Content-Length", "0");
+
+            return (405, None);
+        }
+    };
+
+    if let Some(cache_control) = asset_metadata.cache_control {
+        headers.set("Cache-Control", cache_control);
+    }
+
+    if let Some(etag) = asset_metadata.etag {
+        headers.set("ETag", etag);
+    }
+
+    // Any headers beyond `MAX_ASSET_HEADERS` would take the room `RESPONSE_HEADERS` sets aside for the fixed ones
+    for (index, (name, value)) in asset_metadata.headers.iter().enumerate() {
+        if index < MAX_ASSET_HEADERS {
+            headers.set(name, value);
+        } else {
+            warn!(
+                "Too many headers for asset {}, skipping header {}",
+                asset_metadata.uri, name
+            );
+        }
+    }
+
+    if is_not_modified(asset_metadata, request.header("If-None-Match")) {
+        return (304, None);
+    }
+
+    if let Some(content_encoding) = asset_metadata.content_encoding {
+        headers.set("Content-Encoding", content_encoding);
+        headers.set("Vary", "Accept-Encoding");
+    }
+
+    if let Some(content_type) = asset_metadata.content_type {
+        headers.set("Content-Type", content_type);
+    }
+
+    headers.set("Accept-Ranges", "bytes");
+
+    let (status, body) = match byte_range(
+        request.header("Range"),
+        request.header("If-Range"),
+        asset_metadata,
+        len,
+    ) {
+        ByteRange::Full => (200, Some((0, len))),
+        ByteRange::Partial(start, end) => {
+            write!(content_range, "bytes {}-{}/{}", start, end, len).unwrap();
+            headers.set("Content-Range", content_range);
+
+            (206, Some((start, end + 1)))
+        }
+        ByteRange::Unsatisfiable => {
+            write!(content_range, "bytes */{}", len).unwrap();
+            headers.set("Content-Range", content_range);
+
+            (416, None)
+        }
+    };
+
+    if let Some((start, end)) = body {
+        write!(content_len, "{}", end - start).unwrap();
+        headers.set("Content-Length", content_len);
+    } else {
+        headers.set("Content-Length", "0");
+    }
+
+    if head {
+        (status, None)
+    } else {
+        (status, body)
+    }
+}
+
+/// Checks whether the client already has the current version of the asset,
+/// i.e. whether any of the entity tags in `If-None-Match` matches its ETag.
+fn is_not_modified(asset_metadata: &AssetMetadata, if_none_match: Option<&str>) -> bool {
+    match (asset_metadata.etag, if_none_match) {
+        (Some(etag), Some(if_none_match)) => if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag),
+        _ => false,
+    }
+}
+
+/// The part of an asset requested by the `Range` header of a request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// Inclusive start and end offsets
+    Partial(usize, usize),
+    Unsatisfiable,
+}
+
+/// Evaluates a `Range` request header against an asset of length `len`.
+///
+/// Only single byte ranges are supported; multiple ranges are answered with the full asset,
+/// as is a range conditioned by an `If-Range` entity tag which does not match the current ETag.
+fn byte_range(
+    range: Option<&str>,
+    if_range: Option<&str>,
+    asset_metadata: &AssetMetadata,
+    len: usize,
+) -> ByteRange {
+    let Some(range) = range.and_then(|range| range.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+
+    if let Some(if_range) = if_range {
+        if asset_metadata.etag != Some(if_range.trim()) {
+            return ByteRange::Full;
+        }
+    }
+
+    if range.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let Some((start, end)) = range.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return ByteRange::Full,
+        ("", suffix) => match suffix.parse::<usize>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<usize>() {
+            Ok(start) => (start, len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<usize>(), end.parse::<usize>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+/// Checks whether a content encoding is acceptable as per the `Accept-Encoding` request header.
+///
+/// The identity encoding is always acceptable, while a missing header is treated as
+/// the client not supporting any compression.
+fn is_accepted(accept_encoding: Option<&str>, content_encoding: Option<&str>) -> bool {
+    match (accept_encoding, content_encoding) {
+        (_, None) => true,
+        (None, Some(_)) => false,
+        (Some(accept_encoding), Some(content_encoding)) => {
+            // An explicit entry for the coding takes precedence over `*`, so that `br;q=0, *` still refuses `br`
+            let mut wildcard = None;
+
+            for coding in accept_encoding.split(',') {
+                let mut params = coding.split(';').map(str::trim);
+
+                let name = params.next().unwrap_or("");
+
+                let rejected = params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .map(|q| q <= 0.0)
+                        .unwrap_or(false)
+                });
+
+                if name.eq_ignore_ascii_case(content_encoding) {
+                    return !rejected;
+                } else if name == "*" {
+                    wildcard = Some(!rejected);
+                }
+            }
+
+            wildcard.unwrap_or(false)
+        }
+    }
+}
+
+/// The handlers and serving functions for the blocking `embedded-svc` servers.
+#[cfg(feature = "assets-serve")]
+mod svc {
+    use embedded_svc::http::server::{Connection, Handler, HandlerResult, Request};
+    use embedded_svc::http::Method;
+    use embedded_svc::io::Write;
+
+    use super::{
+        lookup, lookup_source, prepare_response, Asset, AssetMetadata, AssetSource, Assets,
+        RequestMethod, ResponseHeaders, DATA_CHUNK_SIZE, EMPTY_BODY_HEADERS, MAX_ASSET_HEADERS,
+        METADATA_BUF_SIZE, RESPONSE_HEADERS,
+    };
+
+    impl<C: Connection> super::AssetRequest for Request<C> {
+        fn method(&self) -> RequestMethod {
+            request_method(embedded_svc::http::Query::method(self))
+        }
+
+        fn uri(&self) -> &str {
+            embedded_svc::http::Query::uri(self)
+        }
+
+        fn header(&self, name: &str) -> Option<&str> {
+            embedded_svc::http::Headers::header(self, name)
+        }
+    }
+
+    pub(super) fn request_method(method: Method) -> RequestMethod {
+        match method {
+            Method::Get => RequestMethod::Get,
+            Method::Head => RequestMethod::Head,
+            _ => RequestMethod::Other,
+        }
+    }
+
+    pub struct AssetsHandler(Assets);
+
+    impl AssetsHandler {
+        pub const fn new(assets: Assets) -> Self {
+            Self(assets)
+        }
+    }
+
+    impl<C: Connection> Handler<C> for AssetsHandler {
+        fn handle(&self, connection: &mut C) -> HandlerResult {
+            serve_assets(Request::wrap(connection), self.0)
+        }
+    }
+
+    pub fn serve_assets<C: Connection>(request: Request<C>, assets: Assets) -> HandlerResult {
+        if let Some((asset_metadata, data)) =
+            lookup(assets, request.uri(), request.header("Accept-Encoding"))
+        {
+            serve_asset_data(request, asset_metadata, data)
+        } else {
+            request.into_response(404, None, EMPTY_BODY_HEADERS)?;
+
+            Ok(())
+        }
+    }
+
+    /// Serves the asset matching the request URI from any `AssetSource`, e.g. an `Archive`.
+    pub fn serve_source<C: Connection, S: AssetSource>(
+        request: Request<C>,
+        source: &mut S,
+    ) -> HandlerResult {
+        let Some(index) = lookup_source(source, request.uri(), request.header("Accept-Encoding"))?
+        else {
+            request.into_response(404, None, EMPTY_BODY_HEADERS)?;
+
+            return Ok(());
+        };
+
+        let mut buf = [0_u8; METADATA_BUF_SIZE];
+        let mut asset_headers = [("", ""); MAX_ASSET_HEADERS];
+
+        let (asset_metadata, len) = source.metadata(index, &mut buf, &mut asset_headers)?;
+
+        let mut content_range = heapless::String::<48>::new();
+        let mut content_len = heapless::String::<20>::new();
+
+        let mut headers = ResponseHeaders::<RESPONSE_HEADERS>::new();
+
+        let (status, body) = prepare_response(
+            &mut headers,
+            &mut content_range,
+            &mut content_len,
+            &asset_metadata,
+            len,
+            &request,
+        );
+
+        let mut response = request.into_response(status, None, headers.as_slice())?;
+
+        if let Some((start, end)) = body {
+            let mut chunk = [0_u8; DATA_CHUNK_SIZE];
+
+            let mut offset = start;
+
+            while offset < end {
+                let size = (end - offset).min(chunk.len());
+                let read = source.read_data(index, offset, &mut chunk[..size])?;
+
+                if read == 0 {
+                    break;
+                }
+
+                response.write_all(&chunk[..read])?;
+
+                offset += read;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn serve<C: Connection>(request: Request<C>, asset: Asset) -> HandlerResult {
+        serve_asset_data(request, AssetMetadata::derive(asset.0), asset.1)
+    }
+
+    pub fn serve_asset_data<C: Connection>(
+        request: Request<C>,
+        asset_metadata: AssetMetadata<'static>,
+        data: &'static [u8],
+    ) -> HandlerResult {
+        let mut content_range = heapless::String::<48>::new();
+        let mut content_len = heapless::String::<20>::new();
+
+        let mut headers = ResponseHeaders::<RESPONSE_HEADERS>::new();
+
+        let (status, body) = prepare_response(
+            &mut headers,
+            &mut content_range,
+            &mut content_len,
+            &asset_metadata,
+            data.len(),
+            &request,
+        );
+
+        let mut response = request.into_response(status, None, headers.as_slice())?;
+
+        if let Some((start, end)) = body {
+            response.write_all(&data[start..end])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Async asset serving, usable on stable Rust with any async HTTP server implementing `asynch::Connection`.
+///
+/// `RawConnection` implements it on top of any `embedded_io_async::Write`, e.g. the socket of a server
+/// parsing the requests itself. With the `assets-serve` and `nightly` features, `Connection` is also implemented
+/// for the requests of the async `embedded-svc` servers, and the handlers implement their `Handler` trait.
+///
+/// Unlike their blocking counterparts, the serving functions take the connection by reference and return
+/// the error of the connection (or `ServeError`) rather than a `HandlerResult`, so that they do not depend
+/// on `embedded-svc`. Handlers of the async `embedded-svc` servers calling them migrate from
+/// `serve_asset_data(request, metadata, data).await` to `serve_asset_data(&mut request, metadata, data).await?; Ok(())`,
+/// while the ones using `AssetHandler` and `AssetsHandler` need no changes.
+pub mod asynch {
+    use core::fmt::{Debug, Write as _};
+    use core::future::Future;
+
+    use super::{
+        prepare_response, ResponseHeaders, DATA_CHUNK_SIZE, EMPTY_BODY_HEADERS, MAX_ASSET_HEADERS,
+        METADATA_BUF_SIZE, RESPONSE_HEADERS,
+    };
+
+    pub use super::{
+        lookup, lookup_source, Asset, AssetMetadata, AssetRequest, AssetSource, Assets,
+        RequestMethod,
+    };
+
+    /// A request of an async HTTP server, through which its response is sent.
+    pub trait Connection: AssetRequest {
+        type Error: Debug;
+
+        /// Sends the status line and the headers of the response.
+        fn initiate_response<'a>(
+            &'a mut self,
+            status: u16,
+            message: Option<&'a str>,
+            headers: &'a [(&'a str, &'a str)],
+        ) -> impl Future<Output = Result<(), Self::Error>> + 'a;
+
+        /// Writes a part of the response body, once the response has been initiated.
+        fn write_all<'a>(
+            &'a mut self,
+            buf: &'a [u8],
+        ) -> impl Future<Output = Result<(), Self::Error>> + 'a;
+    }
+
+    /// The error of `serve_source`: either the response could not be sent, or the asset could not be read.
+    #[derive(Debug)]
+    pub enum ServeError<C, S> {
+        Connection(C),
+        Source(S),
+    }
+
+    pub struct AssetHandler(AssetMetadata<'static>, &'static [u8]);
+
+    impl AssetHandler {
+        pub const fn new(metadata: AssetMetadata<'static>, data: &'static [u8]) -> Self {
+            Self(metadata, data)
+        }
+
+        pub fn from_asset(asset: Asset) -> Self {
+            Self(AssetMetadata::derive(asset.0), asset.1)
+        }
+
+        pub async fn serve<C: Connection>(&self, connection: &mut C) -> Result<(), C::Error> {
+            serve_asset_data(connection, self.0.clone(), self.1).await
+        }
+    }
+
+    pub struct AssetsHandler(Assets);
+
+    impl AssetsHandler {
+        pub const fn new(assets: Assets) -> Self {
+            Self(assets)
+        }
+
+        pub async fn serve<C: Connection>(&self, connection: &mut C) -> Result<(), C::Error> {
+            serve_assets(connection, self.0).await
+        }
+    }
+
+    pub async fn serve_assets<C: Connection>(
+        connection: &mut C,
+        assets: Assets,
+    ) -> Result<(), C::Error> {
+        if let Some((asset_metadata, data)) = lookup(
+            assets,
+            connection.uri(),
+            connection.header("Accept-Encoding"),
+        ) {
+            serve_asset_data(connection, asset_metadata, data).await
+        } else {
+            connection
+                .initiate_response(404, None, EMPTY_BODY_HEADERS)
+                .await
+        }
+    }
+
+    /// Serves the asset matching the request URI from any `AssetSource`, e.g. an `Archive`.
+    ///
+    /// Note that the source itself is read in a blocking manner.
+    pub async fn serve_source<C: Connection, S: AssetSource>(
+        connection: &mut C,
+        source: &mut S,
+    ) -> Result<(), ServeError<C::Error, S::Error>> {
+        let Some(index) = lookup_source(
+            source,
+            connection.uri(),
+            connection.header("Accept-Encoding"),
+        )
+        .map_err(ServeError::Source)?
+        else {
+            return connection
+                .initiate_response(404, None, EMPTY_BODY_HEADERS)
+                .await
+                .map_err(ServeError::Connection);
+        };
+
+        let mut buf = [0_u8; METADATA_BUF_SIZE];
+        let mut asset_headers = [("", ""); MAX_ASSET_HEADERS];
+
+        let (asset_metadata, len) = source
+            .metadata(index, &mut buf, &mut asset_headers)
+            .map_err(ServeError::Source)?;
+
+        let mut content_range = heapless::String::<48>::new();
+        let mut content_len = heapless::String::<20>::new();
+
+        let mut headers = ResponseHeaders::<RESPONSE_HEADERS>::new();
+
+        let (status, body) = prepare_response(
+            &mut headers,
+            &mut content_range,
+            &mut content_len,
+            &asset_metadata,
+            len,
+            connection,
+        );
+
+        connection
+            .initiate_response(status, None, headers.as_slice())
+            .await
+            .map_err(ServeError::Connection)?;
+
+        if let Some((start, end)) = body {
+            let mut chunk = [0_u8; DATA_CHUNK_SIZE];
+
+            let mut offset = start;
+
+            while offset < end {
+                let size = (end - offset).min(chunk.len());
+                let read = source
+                    .read_data(index, offset, &mut chunk[..size])
+                    .map_err(ServeError::Source)?;
+
+                if read == 0 {
+                    break;
+                }
+
+                connection
+                    .write_all(&chunk[..read])
+                    .await
+                    .map_err(ServeError::Connection)?;
+
+                offset += read;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn serve<C: Connection>(connection: &mut C, asset: Asset) -> Result<(), C::Error> {
+        serve_asset_data(connection, AssetMetadata::derive(asset.0), asset.1).await
+    }
+
+    pub async fn serve_asset_data<C: Connection>(
+        connection: &mut C,
+        asset_metadata: AssetMetadata<'static>,
+        data: &'static [u8],
+    ) -> Result<(), C::Error> {
+        let mut content_range = heapless::String::<48>::new();
+        let mut content_len = heapless::String::<20>::new();
+
+        let mut headers = ResponseHeaders::<RESPONSE_HEADERS>::new();
+
+        let (status, body) = prepare_response(
+            &mut headers,
+            &mut content_range,
+            &mut content_len,
+            &asset_metadata,
+            data.len(),
+            connection,
+        );
+
+        connection
+            .initiate_response(status, None, headers.as_slice())
+            .await?;
+
+        if let Some((start, end)) = body {
+            connection.write_all(&data[start..end]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// A `Connection` writing an HTTP/1.1 response into any `embedded_io_async::Write`,
+    /// for servers which parse the request line and headers themselves.
+    pub struct RawConnection<'r, W> {
+        method: RequestMethod,
+        uri: &'r str,
+        headers: &'r [(&'r str, &'r str)],
+        write: W,
+    }
+
+    impl<'r, W> RawConnection<'r, W> {
+        /// Wraps `write`, the connection the request with `method`, `uri` and `headers` was read from.
+        pub const fn new(
+            method: RequestMethod,
+            uri: &'r str,
+            headers: &'r [(&'r str, &'r str)],
+            write: W,
+        ) -> Self {
+            Self {
+                method,
+                uri,
+                headers,
+                write,
+            }
+        }
+
+        pub fn release(self) -> W {
+            self.write
+        }
+    }
+
+    impl<'r, W> AssetRequest for RawConnection<'r, W> {
+        fn method(&self) -> RequestMethod {
+            self.method
+        }
+
+        fn uri(&self) -> &str {
+            self.uri
+        }
+
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| *value)
+        }
+    }
+
+    impl<'r, W> Connection for RawConnection<'r, W>
+    where
+        W: embedded_io_async::Write,
+    {
+        type Error = W::Error;
+
+        async fn initiate_response<'a>(
+            &'a mut self,
+            status: u16,
+            message: Option<&'a str>,
+            headers: &'a [(&'a str, &'a str)],
+        ) -> Result<(), Self::Error> {
+            let mut status_code = heapless::String::<8>::new();
+            write!(status_code, "{} ", status).unwrap();
+
+            self.write.write_all(b"HTTP/1.1 ").await?;
+            self.write.write_all(status_code.as_bytes()).await?;
+            self.write
+                .write_all(message.unwrap_or_else(|| reason(status)).as_bytes())
+                .await?;
+            self.write.write_all(b"\r\n").await?;
+
+            for (name, value) in headers {
+                self.write.write_all(name.as_bytes()).await?;
+                self.write.write_all(b": ").await?;
+                self.write.write_all(value.as_bytes()).await?;
+                self.write.write_all(b"\r\n").await?;
+            }
+
+            self.write.write_all(b"\r\n").await
+        }
+
+        fn write_all<'a>(
+            &'a mut self,
+            buf: &'a [u8],
+        ) -> impl Future<Output = Result<(), Self::Error>> + 'a {
+            self.write.write_all(buf)
+        }
+    }
+
+    /// The reason phrases of the statuses of asset responses.
+    fn reason(status: u16) -> &'static str {
+        match status {
+            200 => "OK",
+            206 => "Partial Content",
+            304 => "Not Modified",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            416 => "Range Not Satisfiable",
+            _ => "",
+        }
+    }
+
+    #[cfg(all(feature = "assets-serve", feature = "nightly"))]
+    mod svc {
+        use core::future::Future;
+
+        use embedded_svc::http::server::asynch::{self, Handler, HandlerResult, Request};
+
+        use super::{
+            serve_asset_data, serve_assets, AssetHandler, AssetRequest, AssetsHandler, Connection,
+            RequestMethod,
+        };
+
+        impl<C: asynch::Connection> AssetRequest for Request<C> {
+            fn method(&self) -> RequestMethod {
+                super::super::svc::request_method(embedded_svc::http::Query::method(self))
+            }
+
+            fn uri(&self) -> &str {
+                embedded_svc::http::Query::uri(self)
+            }
+
+            fn header(&self, name: &str) -> Option<&str> {
+                embedded_svc::http::Headers::header(self, name)
+            }
+        }
+
+        impl<C: asynch::Connection> Connection for Request<C> {
+            type Error = C::Error;
+
+            fn initiate_response<'a>(
+                &'a mut self,
+                status: u16,
+                message: Option<&'a str>,
+                headers: &'a [(&'a str, &'a str)],
+            ) -> impl Future<Output = Result<(), Self::Error>> + 'a {
+                self.connection()
+                    .initiate_response(status, message, headers)
+            }
+
+            fn write_all<'a>(
+                &'a mut self,
+                buf: &'a [u8],
+            ) -> impl Future<Output = Result<(), Self::Error>> + 'a {
+                self.connection().write_all(buf)
+            }
+        }
+
+        impl<C: asynch::Connection> Handler<C> for AssetHandler {
+            type HandleFuture<'a>
+                = impl Future<Output = HandlerResult>
+            where
+                Self: 'a,
+                C: 'a;
+
+            fn handle<'a>(&'a self, connection: &'a mut C) -> Self::HandleFuture<'a> {
+                async move {
+                    serve_asset_data(&mut Request::wrap(connection), self.0.clone(), self.1)
+                        .await?;
+
+                    Ok(())
+                }
+            }
+        }
+
+        impl<C: asynch::Connection> Handler<C> for AssetsHandler {
+            type HandleFuture<'a>
+                = impl Future<Output = HandlerResult>
+            where
+                Self: 'a,
+                C: 'a;
+
+            fn handle<'a>(&'a self, connection: &'a mut C) -> Self::HandleFuture<'a> {
+                async move {
+                    serve_assets(&mut Request::wrap(connection), self.0).await?;
+
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    use super::asynch::{serve_assets, RawConnection, RequestMethod};
+    use super::{byte_range, is_accepted, AssetMetadata, Assets, ByteRange};
+
+    const ASSETS: Assets = &[(
+        AssetMetadata {
+            uri: "/hello.txt",
+            cache_control: Some("no-cache"),
+            content_encoding: None,
+            content_type: Some("text/plain"),
+            etag: Some("\"1234\""),
+            headers: &[("X-Content-Type-Options", "nosniff")],
+        },
+        b"Hello, world!",
+    )];
+
+    /// Polls a future which never waits, as the in-memory `RawConnection` ones do.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(
+            |_| RawWaker::new(core::ptr::null(), &VTABLE),
+            |_| (),
+            |_| (),
+            |_| (),
+        );
+
+        let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+
+        match pin!(future).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("The future is not ready"),
+        }
+    }
+
+    struct Response(heapless::Vec<u8, 512>);
+
+    impl embedded_io_async::ErrorType for Response {
+        type Error = Infallible;
+    }
+
+    impl embedded_io_async::Write for Response {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.0.extend_from_slice(buf).unwrap();
+
+            Ok(buf.len())
+        }
+    }
+
+    fn serve(method: RequestMethod, uri: &str, headers: &[(&str, &str)]) -> heapless::String<512> {
+        serve_from(ASSETS, method, uri, headers)
+    }
+
+    fn serve_from(
+        assets: Assets,
+        method: RequestMethod,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> heapless::String<512> {
+        let mut connection =
+            RawConnection::new(method, uri, headers, Response(heapless::Vec::new()));
+
+        block_on(serve_assets(&mut connection, assets)).unwrap();
+
+        let response = connection.release().0;
+
+        heapless::String::from(core::str::from_utf8(&response).unwrap())
+    }
+
+    #[test]
+    fn raw_connection() {
+        assert_eq!(
+            serve(RequestMethod::Get, "/hello.txt?x=1", &[]),
+            "HTTP/1.1 200 OK\r\n\
+             Cache-Control: no-cache\r\n\
+             ETag: \"1234\"\r\n\
+             X-Content-Type-Options: nosniff\r\n\
+             Content-Type: text/plain\r\n\
+             Accept-Ranges: bytes\r\n\
+             Content-Length: 13\r\n\
+             \r\n\
+             Hello, world!"
+        );
+
+        let head = serve(RequestMethod::Head, "/hello.txt", &[("range", "bytes=7-")]);
+
+        assert!(
+            head.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+            "{}",
+            head
+        );
+        assert!(
+            head.contains("Content-Range: bytes 7-12/13\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.ends_with("Content-Length: 6\r\n\r\n"), "{}", head);
+
+        assert_eq!(
+            serve(
+                RequestMethod::Get,
+                "/hello.txt",
+                &[("If-None-Match", "\"1234\"")]
+            ),
+            "HTTP/1.1 304 Not Modified\r\n\
+             Cache-Control: no-cache\r\n\
+             ETag: \"1234\"\r\n\
+             X-Content-Type-Options: nosniff\r\n\
+             \r\n"
+        );
+
+        assert_eq!(
+            serve(RequestMethod::Other, "/hello.txt", &[]),
+            "HTTP/1.1 405 Method Not Allowed\r\n\
+             Allow: GET, HEAD\r\n\
+             Content-Length: 0\r\n\
+             \r\n"
+        );
+
+        assert_eq!(
+            serve(RequestMethod::Get, "/missing.txt", &[]),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        let unsatisfiable = serve(RequestMethod::Get, "/hello.txt", &[("Range", "bytes=13-")]);
+
+        assert!(
+            unsatisfiable.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"),
+            "{}",
+            unsatisfiable
+        );
+        assert!(
+            unsatisfiable.ends_with("Content-Range: bytes */13\r\nContent-Length: 0\r\n\r\n"),
+            "{}",
+            unsatisfiable
+        );
+    }
+
+    #[test]
+    fn too_many_headers() {
+        const HEADERS: &[(&str, &str)] = &[
+            ("X-0", "0"),
+            ("X-1", "1"),
+            ("X-2", "2"),
+            ("X-3", "3"),
+            ("X-4", "4"),
+            ("X-5", "5"),
+            ("X-6", "6"),
+            ("X-7", "7"),
+            ("X-8", "8"),
+            ("X-9", "9"),
+            ("X-10", "10"),
+            ("X-11", "11"),
+            ("X-12", "12"),
+            ("X-13", "13"),
+            ("X-14", "14"),
+        ];
+
+        const ASSETS: Assets = &[(
+            AssetMetadata {
+                uri: "/hello.txt",
+                cache_control: Some("no-cache"),
+                content_encoding: Some("br"),
+                content_type: Some("text/plain"),
+                etag: Some("\"1234\""),
+                headers: HEADERS,
+            },
+            b"Hello, world!",
+        )];
+
+        let response = serve_from(
+            ASSETS,
+            RequestMethod::Get,
+            "/hello.txt",
+            &[("Accept-Encoding", "br"), ("Range", "bytes=0-4")],
+        );
+
+        assert!(
+            response.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("X-7: 7\r\n"), "{}", response);
+        assert!(!response.contains("X-8: 8\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nHello"), "{}", response);
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let asset_metadata = &ASSETS[0].0;
+
+        let cases = [
+            (None, None, 13, ByteRange::Full),
+            (Some("bytes=0-4"), None, 13, ByteRange::Partial(0, 4)),
+            (Some(" bytes=7-"), None, 13, ByteRange::Partial(7, 12)),
+            (Some("bytes=5-100"), None, 13, ByteRange::Partial(5, 12)),
+            (Some("bytes=12-12"), None, 13, ByteRange::Partial(12, 12)),
+            // Suffix ranges
+            (Some("bytes=-5"), None, 13, ByteRange::Partial(8, 12)),
+            (Some("bytes=-20"), None, 13, ByteRange::Partial(0, 12)),
+            (Some("bytes=-0"), None, 13, ByteRange::Unsatisfiable),
+            // Invalid ranges are ignored
+            (Some("bytes=5-3"), None, 13, ByteRange::Full),
+            (Some("bytes=-"), None, 13, ByteRange::Full),
+            (Some("bytes=a-b"), None, 13, ByteRange::Full),
+            (Some("items=0-4"), None, 13, ByteRange::Full),
+            // Ranges starting past the end
+            (Some("bytes=13-"), None, 13, ByteRange::Unsatisfiable),
+            (Some("bytes=20-30"), None, 13, ByteRange::Unsatisfiable),
+            // Multiple ranges are not supported
+            (Some("bytes=0-1, 3-4"), None, 13, ByteRange::Full),
+            // `If-Range`
+            (
+                Some("bytes=0-4"),
+                Some("\"1234\""),
+                13,
+                ByteRange::Partial(0, 4),
+            ),
+            (Some("bytes=0-4"), Some("\"5678\""), 13, ByteRange::Full),
+            (
+                Some("bytes=0-4"),
+                Some("Wed, 21 Oct 2015 07:28:00 GMT"),
+                13,
+                ByteRange::Full,
+            ),
+            (None, Some("\"1234\""), 13, ByteRange::Full),
+            // A zero-length asset
+            (None, None, 0, ByteRange::Full),
+            (Some("bytes=0-"), None, 0, ByteRange::Unsatisfiable),
+            (Some("bytes=0-0"), None, 0, ByteRange::Unsatisfiable),
+            (Some("bytes=-5"), None, 0, ByteRange::Unsatisfiable),
+        ];
+
+        for (range, if_range, len, expected) in cases {
+            assert_eq!(
+                byte_range(range, if_range, asset_metadata, len),
+                expected,
+                "Range: {range:?}, If-Range: {if_range:?}, length: {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn accept_encoding() {
+        let cases = [
+            (None, None, true),
+            (None, Some("br"), false),
+            (Some("gzip, br"), Some("br"), true),
+            (Some("gzip"), Some("br"), false),
+            (Some("BR"), Some("br"), true),
+            (Some("br;q=0"), Some("br"), false),
+            (Some("br; q=0.5"), Some("br"), true),
+            (Some("*"), Some("br"), true),
+            (Some("*;q=0"), Some("br"), false),
+            (Some("br;q=0, *"), Some("br"), false),
+            (Some("*, br;q=0"), Some("br"), false),
+            (Some("br, *;q=0"), Some("br"), true),
+            (Some("gzip;q=0, *"), Some("br"), true),
+        ];
+
+        for (accept_encoding, content_encoding, accepted) in cases {
+            assert_eq!(
+                is_accepted(accept_encoding, content_encoding),
+                accepted,
+                "Accept-Encoding: {accept_encoding:?}, Content-Encoding: {content_encoding:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::rc::Rc;
+
+use yew::prelude::*;
+use yewdux_middleware::*;
+
+use crate::frame::*;
+
+/// The state of the connection to the device, as reported by `middleware::WsConnection`.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Store)]
+pub struct ConnectionStore(pub Option<ConnectionState>);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Failed,
+}
+
+impl Reducer<ConnectionStore> for ConnectionState {
+    fn apply(&self, mut store: Rc<ConnectionStore>) -> Rc<ConnectionStore> {
+        let state = Rc::make_mut(&mut store);
+
+        state.0 = Some(*self);
+
+        store
+    }
+}
+
+#[derive(Properties, Clone, Debug, PartialEq)]
+pub struct ConnectionStatusItemProps {
+    #[prop_or_default]
+    pub selected: Callback<()>,
+}
+
+/// Shows in the status bar whether the connection to the device is being (re)established or is lost,
+/// i.e. whether the data displayed might be stale.
+#[function_component(ConnectionStatusItem)]
+pub fn connection_status_item(props: &ConnectionStatusItemProps) -> Html {
+    let connection = use_store_value::<ConnectionStore>();
+
+    let (icon, text) = match connection.0 {
+        Some(ConnectionState::Connecting) => ("fa-lg fa-solid fa-spinner fa-pulse", "connecting"),
+        Some(ConnectionState::Failed) => (
+            "fa-lg fa-solid fa-plug-circle-xmark has-text-danger",
+            "offline",
+        ),
+        _ => return html! {},
+    };
+
+    html! {
+        <StatusItem
+            {icon}
+            {text}
+            selected={props.selected.clone()}/>
+    }
+}
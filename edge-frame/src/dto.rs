@@ -1,4 +1,5 @@
 pub use role::*;
+pub use ws::*;
 
 mod role {
     use enumset::*;
@@ -35,3 +36,17 @@ mod role {
         Admin,
     }
 }
+
+mod ws {
+    use serde::{Deserialize, Serialize};
+
+    /// A message exchanged over the WebSocket of the UI.
+    ///
+    /// The device replies to a request with an `id` by sending an event with the same `id`,
+    /// while unsolicited events, as well as requests not expecting a reply, have none.
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Envelope<T> {
+        pub id: Option<u32>,
+        pub payload: T,
+    }
+}
@@ -172,6 +172,9 @@ pub struct StatusItemProps {
     /// The icon to display.
     #[prop_or_default]
     pub icon: String,
+    /// The text to display next to the icon, if any.
+    #[prop_or_default]
+    pub text: String,
 }
 
 #[function_component(StatusItem)]
@@ -182,10 +185,21 @@ pub fn status_item(props: &StatusItemProps) -> Html {
         Callback::from(move |_| selected.emit(()))
     };
 
-    html! {
-        <div class="icon is-large">
-            <i class={props.icon.clone()} {onclick}></i>
-        </div>
+    if props.text.is_empty() {
+        html! {
+            <div class="icon is-large">
+                <i class={props.icon.clone()} {onclick}></i>
+            </div>
+        }
+    } else {
+        html! {
+            <div class="icon-text" {onclick}>
+                <span class="icon is-large">
+                    <i class={props.icon.clone()}></i>
+                </span>
+                <span>{props.text.clone()}</span>
+            </div>
+        }
     }
 }
 
@@ -1,25 +1,26 @@
 #![allow(clippy::let_unit_value)]
 #![cfg_attr(
-    any(feature = "assets-serve", all(feature = "dto", not(feature = "web"))),
+    any(
+        all(feature = "assets-serve-core", not(feature = "assets-prepare")),
+        all(feature = "dto", not(feature = "web"))
+    ),
     no_std
 )]
 #![cfg_attr(
     all(feature = "nightly", feature = "assets-serve"),
-    feature(type_alias_impl_trait)
+    feature(type_alias_impl_trait, impl_trait_in_assoc_type)
 )]
 #![cfg_attr(feature = "web", recursion_limit = "1024")]
 
+// `assets-prepare` and `assets-serve-core` can be combined, which is how the archive format is tested end to end
 #[cfg(any(
-    all(feature = "assets-prepare", feature = "assets-serve"),
     all(feature = "assets-prepare", feature = "web"),
     all(feature = "assets-prepare", feature = "dto")
 ))]
-compile_error!(
-    "Feature `assets-prepare` is not compatible with features `assets-serve`, `web` and `dto`."
-);
+compile_error!("Feature `assets-prepare` is not compatible with features `web` and `dto`.");
 
-#[cfg(all(feature = "assets-serve", feature = "web"))]
-compile_error!("Feature `assets-serve` is not compatible with feature `web`.");
+#[cfg(all(feature = "assets-serve-core", feature = "web"))]
+compile_error!("Feature `assets-serve-core` is not compatible with feature `web`.");
 
 #[cfg(all(feature = "middleware-ws", feature = "middleware-local"))]
 compile_error!("Only one of the features `middleware-ws` and `middleware-local` can be enabled.");
@@ -32,6 +33,7 @@ pub use web::*;
 mod web {
     pub mod auth;
     pub mod callback2;
+    pub mod connection;
     pub mod field;
     pub mod frame;
     pub mod loading;
@@ -41,7 +43,7 @@ mod web {
     pub mod wifi;
 }
 
-#[cfg(any(feature = "assets-serve", feature = "assets-prepare"))]
+#[cfg(any(feature = "assets-serve-core", feature = "assets-prepare"))]
 pub mod assets;
 
 #[cfg(feature = "dto")]
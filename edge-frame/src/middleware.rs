@@ -92,21 +92,27 @@ mod local {
 #[cfg(feature = "middleware-ws")]
 mod ws {
     use core::cell::RefCell;
-    use core::fmt::Debug;
+    use core::fmt::{self, Debug};
+    use core::task::Poll;
 
     extern crate alloc;
+    use alloc::rc::Rc;
     use alloc::sync::Arc;
 
+    use std::collections::HashMap;
+
     use serde::{de::DeserializeOwned, Serialize};
 
-    use log::trace;
+    use log::{info, trace, warn};
 
+    use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+    use futures::channel::oneshot;
+    use futures::future::{poll_fn, select, Either};
     use futures::stream::{SplitSink, SplitStream};
     use futures::{SinkExt, StreamExt};
 
-    use gloo_net::websocket::{futures::WebSocket, Message};
-
-    use postcard::to_allocvec;
+    use gloo_net::websocket::{futures::WebSocket, Message, State};
+    use gloo_timers::future::TimeoutFuture;
 
     use wasm_bindgen::JsError;
     use wasm_bindgen_futures::spawn_local;
@@ -115,17 +121,27 @@ mod ws {
 
     use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
 
+    use crate::connection::{ConnectionState, ConnectionStore};
+    use crate::dto::Envelope;
+
+    /// The query parameter of the page overriding the base URL of the WebSocket endpoints,
+    /// e.g. `http://localhost:8080/?ws=ws://192.168.4.1` to talk to a real device from `trunk serve`.
+    ///
+    /// Only honoured by debug builds, as otherwise a crafted link could send the credentials of the user
+    /// to any host. Release builds should set `BASE_URL` instead.
+    pub const BASE_URL_QUERY_PARAM: &str = "ws";
+
+    /// The base URL of the WebSocket endpoints in case it is not overridden by the page query:
+    /// the `EDGE_FRAME_WS_BASE_URL` environment variable at build time, if set.
+    pub const BASE_URL: Option<&str> = option_env!("EDGE_FRAME_WS_BASE_URL");
+
     pub fn open(
         ws_endpoint: &str,
     ) -> Result<(SplitSink<WebSocket, Message>, SplitStream<WebSocket>), JsError> {
-        open_url(&format!(
-            "ws://{}/{}",
-            web_sys::window().unwrap().location().host().unwrap(),
-            ws_endpoint,
-        ))
+        open_url(&endpoint_url(ws_endpoint))
     }
 
-    fn open_url(
+    pub fn open_url(
         url: &str,
     ) -> Result<(SplitSink<WebSocket, Message>, SplitStream<WebSocket>), JsError> {
         let ws = WebSocket::open(url)?;
@@ -133,39 +149,638 @@ mod ws {
         Ok(ws.split())
     }
 
+    /// The URL of the WebSocket endpoint `ws_endpoint`, relative to `base_url`.
+    pub fn endpoint_url(ws_endpoint: &str) -> String {
+        format!(
+            "{}/{}",
+            base_url().trim_end_matches('/'),
+            ws_endpoint.trim_start_matches('/'),
+        )
+    }
+
+    /// The base URL of the WebSocket endpoints: the `BASE_URL_QUERY_PARAM` of the page (debug builds only),
+    /// or else `BASE_URL`, or else the host the page was loaded from, with `wss` as the scheme
+    /// if the page was loaded over HTTPS.
+    pub fn base_url() -> String {
+        let location = web_sys::window().unwrap().location();
+
+        #[cfg(debug_assertions)]
+        if let Some(base_url) = location
+            .search()
+            .ok()
+            .and_then(|search| web_sys::UrlSearchParams::new_with_str(&search).ok())
+            .and_then(|params| params.get(BASE_URL_QUERY_PARAM))
+            .filter(|base_url| !base_url.is_empty())
+        {
+            return base_url;
+        }
+
+        if let Some(base_url) = BASE_URL {
+            return base_url.to_string();
+        }
+
+        let scheme = if location.protocol().unwrap() == "https:" {
+            "wss"
+        } else {
+            "ws"
+        };
+
+        format!("{}://{}", scheme, location.host().unwrap())
+    }
+
+    /// The encoding of the messages exchanged over the WebSocket.
+    pub trait Codec {
+        fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Message>;
+
+        fn decode<T: DeserializeOwned>(&self, msg: &Message) -> anyhow::Result<T>;
+    }
+
+    /// Encodes messages with postcard into binary frames. The most compact encoding, and the default one.
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct Postcard;
+
+    impl Codec for Postcard {
+        fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Message> {
+            Ok(Message::Bytes(postcard::to_allocvec(value)?))
+        }
+
+        fn decode<T: DeserializeOwned>(&self, msg: &Message) -> anyhow::Result<T> {
+            Ok(postcard::from_bytes(message_bytes(msg))?)
+        }
+    }
+
+    /// Encodes messages as JSON into text frames, for peers which do not speak postcard.
+    #[cfg(feature = "middleware-ws-json")]
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct Json;
+
+    #[cfg(feature = "middleware-ws-json")]
+    impl Codec for Json {
+        fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Message> {
+            Ok(Message::Text(serde_json::to_string(value)?))
+        }
+
+        fn decode<T: DeserializeOwned>(&self, msg: &Message) -> anyhow::Result<T> {
+            Ok(serde_json::from_slice(message_bytes(msg))?)
+        }
+    }
+
+    /// Encodes messages as CBOR into binary frames, for peers which do not speak postcard.
+    #[cfg(feature = "middleware-ws-cbor")]
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct Cbor;
+
+    #[cfg(feature = "middleware-ws-cbor")]
+    impl Codec for Cbor {
+        fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Message> {
+            let mut bytes = Vec::new();
+
+            ciborium::ser::into_writer(value, &mut bytes)?;
+
+            Ok(Message::Bytes(bytes))
+        }
+
+        fn decode<T: DeserializeOwned>(&self, msg: &Message) -> anyhow::Result<T> {
+            Ok(ciborium::de::from_reader(message_bytes(msg))?)
+        }
+    }
+
+    /// The payload of a frame, regardless of whether it is a text or a binary one.
+    fn message_bytes(msg: &Message) -> &[u8] {
+        match msg {
+            Message::Text(text) => text.as_bytes(),
+            Message::Bytes(bytes) => bytes,
+        }
+    }
+
     pub fn send<M>(sender: SplitSink<WebSocket, Message>) -> impl Fn(M)
     where
         M: Serialize + Debug + 'static,
+    {
+        send_with(sender, Postcard)
+    }
+
+    /// Same as `send`, but with messages encoded by `codec` rather than postcard.
+    pub fn send_with<M, C>(sender: SplitSink<WebSocket, Message>, codec: C) -> impl Fn(M)
+    where
+        M: Serialize + Debug + 'static,
+        C: Codec + 'static,
     {
         let sender = Arc::new(Mutex::<CriticalSectionRawMutex, _>::new(sender));
 
         move |msg| {
+            trace!("Sending request: {:?}", msg);
+
+            let msg = match codec.encode(&msg) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    warn!("Cannot encode request: {}", err);
+                    return;
+                }
+            };
+
             let sender = sender.clone();
 
             spawn_local(async move {
-                trace!("Sending request: {:?}", msg);
-
                 let mut guard = sender.lock().await;
 
-                guard
-                    .send(Message::Bytes(to_allocvec(&msg).unwrap()))
-                    .await
-                    .unwrap();
+                if let Err(err) = guard.send(msg).await {
+                    warn!("Cannot send request: {}", err);
+                }
             });
         }
     }
 
-    pub fn receive<M>(mut receiver: SplitStream<WebSocket>)
+    pub fn receive<M>(receiver: SplitStream<WebSocket>)
     where
         M: DeserializeOwned + Debug + 'static,
     {
-        spawn_local(async move {
-            loop {
-                let event = receiver.next().await.unwrap().unwrap();
-                trace!("Received event: {:?}", event);
+        receive_with::<M, _>(receiver, Postcard)
+    }
 
-                dispatch::invoke(event);
+    /// Same as `receive`, but with messages decoded by `codec` rather than postcard.
+    pub fn receive_with<M, C>(mut receiver: SplitStream<WebSocket>, codec: C)
+    where
+        M: DeserializeOwned + Debug + 'static,
+        C: Codec + 'static,
+    {
+        spawn_local(async move {
+            while let Some(msg) = receiver.next().await {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        warn!("Socket failed, no longer receiving events: {}", err);
+                        break;
+                    }
+                };
+
+                match codec.decode::<M>(&msg) {
+                    Ok(event) => {
+                        trace!("Received event: {:?}", event);
+
+                        dispatch::invoke(event);
+                    }
+                    Err(err) => warn!("Cannot decode event: {}", err),
+                }
             }
         });
     }
+
+    /// The delays between the attempts of a `WsConnection` to reopen its socket.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct Backoff {
+        /// The delay before the first attempt, doubled after each failed one
+        pub initial_delay_ms: u32,
+        /// The delay which the doubling stops at
+        pub max_delay_ms: u32,
+    }
+
+    impl Default for Backoff {
+        fn default() -> Self {
+            Self {
+                initial_delay_ms: 500,
+                max_delay_ms: 30_000,
+            }
+        }
+    }
+
+    /// The time after which `WsConnection::request` gives up waiting for the reply, unless configured otherwise.
+    pub const DEFAULT_REQUEST_TIMEOUT_MS: u32 = 5_000;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum RequestError {
+        /// No reply arrived in time
+        Timeout,
+        /// The connection was dropped before the reply arrived
+        Closed,
+        /// The request could not be encoded by the codec of the connection
+        Encode,
+    }
+
+    impl fmt::Display for RequestError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Timeout => write!(f, "Request timed out"),
+                Self::Closed => write!(f, "Connection closed"),
+                Self::Encode => write!(f, "Cannot encode request"),
+            }
+        }
+    }
+
+    /// The requests awaiting their replies, by request id.
+    struct Replies<M> {
+        next_id: u32,
+        pending: HashMap<u32, oneshot::Sender<M>>,
+    }
+
+    /// Removes a request from the ones awaiting their replies when dropped.
+    struct PendingReply<'a, M> {
+        replies: &'a RefCell<Replies<M>>,
+        id: u32,
+    }
+
+    impl<M> Drop for PendingReply<'_, M> {
+        fn drop(&mut self) {
+            self.replies.borrow_mut().pending.remove(&self.id);
+        }
+    }
+
+    /// A WebSocket connection which survives device reboots and network outages.
+    ///
+    /// Whenever the socket fails or gets closed, it is reopened with an exponential backoff.
+    /// Requests sent in the meantime are queued and go out once the socket is open again,
+    /// while the events of type `M` received are dispatched as with `receive`.
+    ///
+    /// All messages are wrapped in an `Envelope`, which allows `request` to pick the reply to a request
+    /// among the events received, and encoded by the `Codec` given when opening the connection.
+    ///
+    /// The state of the connection is kept in the `ConnectionStore`, e.g. for `ConnectionStatusItem` to show it.
+    pub struct WsConnection<M, C = Postcard> {
+        outgoing: UnboundedSender<Message>,
+        replies: Rc<RefCell<Replies<M>>>,
+        codec: C,
+        request_timeout_ms: u32,
+    }
+
+    impl<M, C> Clone for WsConnection<M, C>
+    where
+        C: Clone,
+    {
+        fn clone(&self) -> Self {
+            Self {
+                outgoing: self.outgoing.clone(),
+                replies: self.replies.clone(),
+                codec: self.codec.clone(),
+                request_timeout_ms: self.request_timeout_ms,
+            }
+        }
+    }
+
+    impl<M, C> WsConnection<M, C>
+    where
+        M: DeserializeOwned + Debug + 'static,
+        C: Codec + Clone + 'static,
+    {
+        pub fn open(ws_endpoint: &str, codec: C) -> Self {
+            Self::open_url(&endpoint_url(ws_endpoint), codec, Backoff::default())
+        }
+
+        pub fn open_url(url: &str, codec: C, backoff: Backoff) -> Self {
+            let (sender, receiver) = mpsc::unbounded();
+
+            let replies = Rc::new(RefCell::new(Replies {
+                next_id: 0,
+                pending: HashMap::new(),
+            }));
+
+            spawn_local(run(
+                url.to_string(),
+                codec.clone(),
+                backoff,
+                receiver,
+                replies.clone(),
+            ));
+
+            Self {
+                outgoing: sender,
+                replies,
+                codec,
+                request_timeout_ms: DEFAULT_REQUEST_TIMEOUT_MS,
+            }
+        }
+
+        /// Sets the time after which `request` gives up waiting for the reply.
+        pub fn request_timeout(mut self, request_timeout_ms: u32) -> Self {
+            self.request_timeout_ms = request_timeout_ms;
+            self
+        }
+
+        /// Same as `send`, but over the managed connection.
+        pub fn send<R>(&self) -> impl Fn(R)
+        where
+            R: Serialize + Debug + 'static,
+        {
+            let sender = self.outgoing.clone();
+            let codec = self.codec.clone();
+
+            move |msg| {
+                trace!("Sending request: {:?}", msg);
+
+                let envelope = Envelope {
+                    id: None,
+                    payload: msg,
+                };
+
+                match codec.encode(&envelope) {
+                    Ok(msg) => {
+                        if sender.unbounded_send(msg).is_err() {
+                            warn!("Connection closed, dropping request");
+                        }
+                    }
+                    Err(err) => warn!("Cannot encode request: {}", err),
+                }
+            }
+        }
+
+        /// Sends `msg` and waits for the event the device replies to it with.
+        ///
+        /// The reply is returned rather than dispatched. Note that the request is queued while disconnected,
+        /// so the timeout covers the reconnection as well.
+        pub async fn request<R>(&self, msg: R) -> Result<M, RequestError>
+        where
+            R: Serialize + Debug,
+        {
+            let (sender, receiver) = oneshot::channel();
+
+            let id = {
+                let mut replies = self.replies.borrow_mut();
+
+                let id = replies.next_id;
+                replies.next_id = id.wrapping_add(1);
+                replies.pending.insert(id, sender);
+
+                id
+            };
+
+            // Forgets the request even if this future is dropped before the reply arrives
+            let _pending = PendingReply {
+                replies: &self.replies,
+                id,
+            };
+
+            trace!("Sending request {}: {:?}", id, msg);
+
+            let envelope = Envelope {
+                id: Some(id),
+                payload: msg,
+            };
+
+            let msg = match self.codec.encode(&envelope) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    warn!("Cannot encode request {}: {}", id, err);
+
+                    return Err(RequestError::Encode);
+                }
+            };
+
+            match self.outgoing.unbounded_send(msg) {
+                Ok(()) => match select(receiver, TimeoutFuture::new(self.request_timeout_ms)).await
+                {
+                    Either::Left((Ok(reply), _)) => Ok(reply),
+                    Either::Left((Err(_), _)) => Err(RequestError::Closed),
+                    Either::Right(_) => Err(RequestError::Timeout),
+                },
+                Err(_) => Err(RequestError::Closed),
+            }
+        }
+    }
+
+    async fn run<M, C>(
+        url: String,
+        codec: C,
+        backoff: Backoff,
+        mut outgoing: UnboundedReceiver<Message>,
+        replies: Rc<RefCell<Replies<M>>>,
+    ) where
+        M: DeserializeOwned + Debug + 'static,
+        C: Codec,
+    {
+        let mut delay_ms = backoff.initial_delay_ms;
+
+        // A request which could not be sent because the socket failed
+        let mut pending = None;
+
+        loop {
+            report(ConnectionState::Connecting);
+
+            match WebSocket::open(&url) {
+                Ok(mut ws) => {
+                    if wait_open(&mut ws).await {
+                        info!("Connected to {}", url);
+
+                        report(ConnectionState::Connected);
+
+                        delay_ms = backoff.initial_delay_ms;
+
+                        if !serve(&mut ws, &mut outgoing, &mut pending, &codec, &replies).await {
+                            return;
+                        }
+                    }
+                }
+                Err(_) => warn!("Cannot open {}", url),
+            }
+
+            warn!("Disconnected from {}, reconnecting in {} ms", url, delay_ms);
+
+            report(ConnectionState::Failed);
+
+            TimeoutFuture::new(delay_ms).await;
+
+            delay_ms = delay_ms.saturating_mul(2).min(backoff.max_delay_ms);
+        }
+    }
+
+    /// Updates the `ConnectionStore` directly rather than through a registered dispatch,
+    /// so that apps not showing the state do not have to register one.
+    fn report(state: ConnectionState) {
+        yewdux::dispatch::apply::<ConnectionStore, _>(state);
+    }
+
+    /// Waits until the socket is open or has failed to open, and returns whether it is open.
+    async fn wait_open(ws: &mut WebSocket) -> bool {
+        poll_fn(|cx| {
+            if !matches!(ws.state(), State::Connecting) {
+                return Poll::Ready(());
+            }
+
+            // The sink gets woken by the `open` event, the stream by the `error` and `close` ones,
+            // and a failed socket reports as ready to the sink as well, hence the state checks
+            let _ = ws.poll_ready_unpin(cx);
+
+            if ws.poll_next_unpin(cx).is_ready() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        matches!(ws.state(), State::Open)
+    }
+
+    /// Relays requests and events over an open socket until it fails, in which case `true` is returned,
+    /// or until all `WsConnection` instances are dropped.
+    ///
+    /// A request is only sent while the socket is open, as the browser silently discards the ones sent
+    /// to a closing socket. Otherwise it is kept in `pending`, to be sent once the socket is reopened.
+    async fn serve<M, C>(
+        ws: &mut WebSocket,
+        outgoing: &mut UnboundedReceiver<Message>,
+        pending: &mut Option<Message>,
+        codec: &C,
+        replies: &RefCell<Replies<M>>,
+    ) -> bool
+    where
+        M: DeserializeOwned + Debug + 'static,
+        C: Codec,
+    {
+        loop {
+            if let Some(msg) = pending.take() {
+                if !matches!(ws.state(), State::Open) {
+                    *pending = Some(msg);
+
+                    return true;
+                }
+
+                if let Err(err) = ws.send(msg.clone()).await {
+                    warn!("Sending failed: {:?}", err);
+
+                    *pending = Some(msg);
+
+                    return true;
+                }
+            }
+
+            let next = match select(outgoing.next(), ws.next()).await {
+                Either::Left((msg, _)) => Either::Left(msg),
+                Either::Right((event, _)) => Either::Right(event),
+            };
+
+            match next {
+                Either::Left(Some(msg)) => *pending = Some(msg),
+                Either::Left(None) => return false,
+                Either::Right(Some(Ok(msg))) => dispatch_event(&msg, codec, replies),
+                Either::Right(Some(Err(err))) => {
+                    warn!("Receiving failed: {:?}", err);
+
+                    return true;
+                }
+                Either::Right(None) => return true,
+            }
+        }
+    }
+
+    /// Hands the event over to the request it replies to, or dispatches it if it is an unsolicited one.
+    ///
+    /// Replies to requests which are no longer waiting for them, e.g. because they timed out, are dropped.
+    fn dispatch_event<M, C>(msg: &Message, codec: &C, replies: &RefCell<Replies<M>>)
+    where
+        M: DeserializeOwned + Debug + 'static,
+        C: Codec,
+    {
+        let envelope = match codec.decode::<Envelope<M>>(msg) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                warn!("Cannot decode event: {}", err);
+                return;
+            }
+        };
+
+        let Some(id) = envelope.id else {
+            trace!("Received event: {:?}", envelope.payload);
+
+            dispatch::invoke(envelope.payload);
+
+            return;
+        };
+
+        let reply = replies.borrow_mut().pending.remove(&id);
+
+        match reply {
+            Some(reply) => {
+                trace!("Received reply {}: {:?}", id, envelope.payload);
+
+                // The request future might have been dropped in the meantime
+                let _ = reply.send(envelope.payload);
+            }
+            None => warn!(
+                "Dropping reply {} to a request no longer waiting for it: {:?}",
+                id, envelope.payload
+            ),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use core::fmt::Debug;
+
+        use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+        use gloo_net::websocket::Message;
+
+        use crate::dto::Envelope;
+
+        use super::Codec;
+
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+        struct Event {
+            name: String,
+            value: Option<i32>,
+            flags: Vec<bool>,
+        }
+
+        fn event() -> Event {
+            Event {
+                name: "Zoë".into(),
+                value: Some(-42),
+                flags: vec![true, false],
+            }
+        }
+
+        fn round_trip<C, T>(codec: C, value: &T) -> Message
+        where
+            C: Codec,
+            T: Serialize + DeserializeOwned + PartialEq + Debug,
+        {
+            let msg = codec.encode(value).unwrap();
+
+            assert_eq!(&codec.decode::<T>(&msg).unwrap(), value);
+
+            msg
+        }
+
+        fn round_trips<C: Codec + Copy>(codec: C) -> [Message; 3] {
+            [
+                round_trip(codec, &event()),
+                round_trip(
+                    codec,
+                    &Envelope {
+                        id: Some(7),
+                        payload: event(),
+                    },
+                ),
+                round_trip(
+                    codec,
+                    &Envelope {
+                        id: None,
+                        payload: event(),
+                    },
+                ),
+            ]
+        }
+
+        #[test]
+        fn postcard() {
+            for msg in round_trips(super::Postcard) {
+                assert!(matches!(msg, Message::Bytes(_)));
+            }
+        }
+
+        #[cfg(feature = "middleware-ws-json")]
+        #[test]
+        fn json() {
+            for msg in round_trips(super::Json) {
+                assert!(matches!(msg, Message::Text(_)));
+            }
+        }
+
+        #[cfg(feature = "middleware-ws-cbor")]
+        #[test]
+        fn cbor() {
+            for msg in round_trips(super::Cbor) {
+                assert!(matches!(msg, Message::Bytes(_)));
+            }
+        }
+    }
 }